@@ -0,0 +1,97 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use hyper::Uri;
+use lazy_static::lazy_static;
+use serde::Serialize;
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+
+/// Number of decisions kept for the debug endpoint.
+const CAPACITY: usize = 200;
+const REDACTED: &str = "REDACTED";
+
+lazy_static! {
+    static ref DECISIONS: Mutex<VecDeque<Decision>> = Mutex::new(VecDeque::with_capacity(CAPACITY));
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Injected,
+    Denied,
+    Failed,
+}
+
+/// The result of evaluating one policy for one request. Never contains credentials:
+/// query values are redacted and headers are not recorded at all.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Decision {
+    pub time: String,
+    pub client: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pod: Option<String>,
+    pub method: String,
+    pub uri: String,
+    pub policy: String,
+    pub outcome: Outcome,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Decision {
+    pub fn new(client: String, pod: Option<String>, method: &str, uri: &Uri, policy: String, outcome: Outcome) -> Self {
+        Decision {
+            time: OffsetDateTime::now_utc().format(&Rfc3339).unwrap_or_default(),
+            client,
+            pod,
+            method: method.to_string(),
+            uri: redact(uri),
+            policy,
+            outcome,
+            error: None,
+        }
+    }
+
+    pub fn with_error(mut self, error: impl ToString) -> Self {
+        self.error = Some(error.to_string());
+        self
+    }
+}
+
+pub fn record(decision: Decision) {
+    let mut decisions = DECISIONS.lock().unwrap();
+    if decisions.len() == CAPACITY {
+        decisions.pop_front();
+    }
+    decisions.push_back(decision);
+}
+
+/// Recent decisions, newest first.
+pub fn recent() -> Vec<Decision> {
+    DECISIONS.lock().unwrap().iter().rev().cloned().collect()
+}
+
+/// The URI without userinfo and query values.
+pub fn redact(uri: &Uri) -> String {
+    let mut redacted = String::new();
+    if let Some(scheme) = uri.scheme_str() {
+        redacted.push_str(scheme);
+        redacted.push_str("://");
+    }
+    if let Some(authority) = uri.authority() {
+        // drop userinfo, it may carry a password
+        redacted.push_str(authority.as_str().rsplit('@').next().unwrap_or_default());
+    }
+    redacted.push_str(uri.path());
+
+    if let Some(query) = uri.query() {
+        let keys: Vec<String> = url::form_urlencoded::parse(query.as_bytes())
+            .map(|(key, _)| format!("{}={}", key, REDACTED))
+            .collect();
+        redacted.push('?');
+        redacted.push_str(&keys.join("&"));
+    }
+
+    redacted
+}
@@ -0,0 +1,121 @@
+pub mod decisions;
+
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use anyhow::Result;
+use hyper::{body::Incoming, Method, Request, StatusCode};
+use kube::ResourceExt;
+use serde::Serialize;
+use crate::apis::{pod_meta, policies, proxy_policy::ProxyPolicyMethod};
+use crate::metrics;
+use crate::server::{self, json_response, response};
+use crate::shutdown;
+
+static PODS_SYNCED: AtomicBool = AtomicBool::new(false);
+static POLICIES_SYNCED: AtomicBool = AtomicBool::new(false);
+static NAMESPACES_SYNCED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_pods_synced() {
+    PODS_SYNCED.store(true, Ordering::Relaxed);
+}
+
+pub fn set_policies_synced() {
+    POLICIES_SYNCED.store(true, Ordering::Relaxed);
+}
+
+pub fn set_namespaces_synced() {
+    NAMESPACES_SYNCED.store(true, Ordering::Relaxed);
+}
+
+/// Ready once the initial pod, policy and namespace lists have been loaded, until a shutdown starts.
+pub fn is_ready() -> bool {
+    PODS_SYNCED.load(Ordering::Relaxed)
+        && POLICIES_SYNCED.load(Ordering::Relaxed)
+        && NAMESPACES_SYNCED.load(Ordering::Relaxed)
+        && !shutdown::is_draining()
+}
+
+/// Serves health, readiness and read-only debug endpoints.
+pub async fn serve(addr: SocketAddr) -> Result<()> {
+    server::serve(addr, None, |req| async move { route(req) }).await
+}
+
+fn route(req: Request<Incoming>) -> server::Response {
+    if req.method() != Method::GET {
+        return response(StatusCode::METHOD_NOT_ALLOWED, "method not allowed");
+    }
+
+    match req.uri().path() {
+        "/healthz" => response(StatusCode::OK, "ok"),
+        "/metrics" => metrics::encode(),
+        "/readyz" => {
+            if is_ready() {
+                response(StatusCode::OK, "ok")
+            } else {
+                response(StatusCode::SERVICE_UNAVAILABLE, "initial sync has not completed")
+            }
+        }
+        "/debug/policies" => json_response(&policy_summaries()),
+        "/debug/pods" => json_response(&pod_summaries()),
+        "/debug/decisions" => json_response(&decisions::recent()),
+        _ => response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+#[derive(Serialize)]
+struct PolicySummary {
+    namespace: String,
+    name: String,
+    hosts: Vec<String>,
+    method: ProxyPolicyMethod,
+    secret: String,
+    rules: Vec<String>,
+}
+
+fn policy_summaries() -> Vec<PolicySummary> {
+    policies::all().iter().map(|policy| {
+        let secret = match (&policy.spec.auth.secret.reference, &policy.spec.auth.secret.raw) {
+            (Some(reference), _) => format!(
+                "{}/{}",
+                reference.namespace.clone().unwrap_or_default(),
+                reference.name.clone().unwrap_or_default(),
+            ),
+            (None, Some(_)) => "raw (redacted)".to_string(),
+            (None, None) => String::new(),
+        };
+
+        PolicySummary {
+            namespace: policy.namespace().unwrap_or_default(),
+            name: policy.name_any(),
+            hosts: policy.spec.hosts.clone(),
+            method: policy.spec.auth.method.clone(),
+            secret,
+            rules: policy.spec.rules.iter().map(|rule| rule.name.clone()).collect(),
+        }
+    }).collect()
+}
+
+/// Identifies a cached pod without its annotations, images and owner, the admin port is unauthenticated.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PodSummary {
+    namespace: String,
+    name: String,
+    uid: String,
+    service_account: String,
+    node_name: String,
+}
+
+fn pod_summaries() -> BTreeMap<String, PodSummary> {
+    pod_meta::snapshot().into_iter().map(|(ip, meta)| {
+        let summary = PodSummary {
+            namespace: meta.namespace.clone(),
+            name: meta.name.clone(),
+            uid: meta.uid.clone(),
+            service_account: meta.service_account.clone(),
+            node_name: meta.node_name.clone(),
+        };
+        (ip, summary)
+    }).collect()
+}
@@ -1,2 +1,4 @@
+pub mod policies;
 pub mod proxy_policy;
-pub mod pod_meta;
\ No newline at end of file
+pub mod pod_meta;
+pub mod workloads;
\ No newline at end of file
@@ -1,37 +1,136 @@
 use crossbeam_skiplist::SkipMap;
 use k8s_openapi::api::core::v1::Pod;
-use kube::ResourceExt;
+use kube::{Api, Client, ResourceExt};
+use kube::api::ListParams;
 use lazy_static::lazy_static;
-use log::info;
-use std::collections::BTreeMap;
-use std::sync::Arc;
+use log::{info, warn};
+use std::collections::{BTreeMap, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use anyhow::Result;
+use moka::future::Cache;
 use regorus::Value;
+use serde::{Deserialize, Serialize};
+use crate::apis::workloads::{self, Owner};
+use crate::metrics;
+
+/// How long the result of a cluster-wide lookup is kept, misses included.
+const LOOKUP_TTL: Duration = Duration::from_secs(30);
+const MAX_LOOKUPS: u64 = 10_000;
 
 lazy_static! {
-    static ref PODMETAS: SkipMap<String, Arc<PodMeta>> = SkipMap::new();
+    static ref PODMETAS: SkipMap<IpAddr, Arc<PodMeta>> = SkipMap::new();
+    /// IPs each cached pod is bound to, by pod UID.
+    static ref POD_IPS: SkipMap<String, Vec<IpAddr>> = SkipMap::new();
+    /// Cached pods, by namespace and name.
+    static ref POD_NAMES: SkipMap<(String, String), Arc<PodMeta>> = SkipMap::new();
+    static ref UNKNOWN_PODS: RwLock<UnknownPods> = RwLock::new(UnknownPods::default());
+    /// Pods found by a cluster-wide lookup, by IP.
+    static ref LOOKUPS: Cache<IpAddr, Option<Arc<PodMeta>>> = Cache::builder()
+        .max_capacity(MAX_LOOKUPS)
+        .time_to_live(LOOKUP_TTL)
+        .build();
+}
+
+/// Which pods are watched into the pod cache.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum PodScope {
+    /// every pod of the cluster
+    #[default]
+    Cluster,
+    /// only pods scheduled on the node of the proxy
+    Node,
+}
+
+/// How clients whose IP is missing from the pod cache are identified.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownPods {
+    /// treated as unknown, policies see no `input.meta`
+    #[default]
+    Unknown,
+    /// looked up by IP across the cluster, results are cached briefly
+    Lookup,
 }
 
+#[derive(Serialize, Clone, Debug)]
 pub struct PodMeta {
+    pub uid: String,
     pub name: String,
     pub namespace: String,
+    pub service_account: String,
+    pub node_name: String,
+    /// direct controller of the pod, e.g. its ReplicaSet
+    pub owner: Option<Owner>,
+    /// workload behind the controller, e.g. the Deployment of the ReplicaSet
+    pub workload: Option<Owner>,
+    /// images of the init and regular containers
+    pub images: Vec<String>,
     pub labels: BTreeMap<String, String>,
     pub annotations: BTreeMap<String, String>,
 }
 
 impl PodMeta {
     pub fn from(pod: &Pod) -> Self {
+        let spec = pod.spec.as_ref();
+        let containers = spec.iter()
+            .flat_map(|spec| spec.init_containers.iter().flatten().chain(spec.containers.iter()));
+        let mut images: Vec<String> = Vec::new();
+        for image in containers.filter_map(|container| container.image.clone()) {
+            if !images.contains(&image) {
+                images.push(image);
+            }
+        }
+
         PodMeta {
+            uid: pod.uid().unwrap_or_default(),
             namespace: pod.namespace().unwrap(),
             name: pod.name_any(),
+            service_account: spec
+                .and_then(|spec| spec.service_account_name.clone())
+                .unwrap_or_else(|| "default".to_string()),
+            node_name: spec.and_then(|spec| spec.node_name.clone()).unwrap_or_default(),
+            owner: Owner::controller(&pod.metadata),
+            workload: None,
+            images,
             labels: pod.labels().clone(),
             annotations: pod.annotations().clone(),
         }
     }
 
+    /// Like `from`, with the workload behind the pod's controller read from the API server.
+    pub async fn resolve(pod: &Pod) -> Self {
+        let mut meta = PodMeta::from(pod);
+        if let Some(owner) = &meta.owner {
+            meta.workload = Some(workloads::workload(&meta.namespace, owner).await);
+        }
+        meta
+    }
+
     pub fn as_input(&self) -> Value {
         let mut input: BTreeMap<Value, Value> = BTreeMap::new();
         input.insert(Value::from("name"), Value::from(self.name.as_str()));
         input.insert(Value::from("namespace"), Value::from(self.namespace.as_str()));
+        input.insert(Value::from("uid"), Value::from(self.uid.as_str()));
+        input.insert(Value::from("serviceAccountName"), Value::from(self.service_account.as_str()));
+        input.insert(Value::from("nodeName"), Value::from(self.node_name.as_str()));
+        if let Some(owner) = &self.owner {
+            input.insert(Value::from("owner"), owner.as_input());
+        }
+        if let Some(workload) = &self.workload {
+            input.insert(Value::from("workload"), workload.as_input());
+        }
+        let images: Vec<Value> = self.images.iter().map(|image| Value::from(image.as_str())).collect();
+        input.insert(Value::from("images"), Value::from(images));
+
+        let namespace_labels: BTreeMap<Value, Value> = workloads::namespace_labels(&self.namespace)
+            .into_iter()
+            .map(|(k, v)| (Value::from(k), Value::from(v)))
+            .collect();
+        input.insert(Value::from("namespaceLabels"), Value::from(namespace_labels));
+
         let labels: BTreeMap<Value, Value> = self.labels.clone()
             .into_iter()
             .map(|(k, v)| (Value::from(k), Value::from(v)))
@@ -48,45 +147,216 @@ impl PodMeta {
     }
 }
 
-pub fn find(ip: &String) -> Option<Arc<PodMeta>> {
+/// Copy of the cache keyed by pod IP, for debugging.
+pub fn snapshot() -> BTreeMap<String, Arc<PodMeta>> {
+    PODMETAS.iter()
+        .map(|entry| (entry.key().to_string(), Arc::clone(entry.value())))
+        .collect()
+}
+
+pub fn find(ip: &IpAddr) -> Option<Arc<PodMeta>> {
     if let Some(entry) = PODMETAS.get(ip) {
         return Some(Arc::clone(entry.value()));
     }
     None
 }
 
-pub fn bind(pod: &Pod) {
-    if pod.metadata.deletion_timestamp.is_some() {
+pub fn set_unknown_pods(policy: UnknownPods) {
+    *UNKNOWN_PODS.write().unwrap() = policy;
+}
+
+/// The pod with the IP, looked up across the cluster when it is not cached and lookups are enabled.
+pub async fn resolve(ip: IpAddr) -> Option<Arc<PodMeta>> {
+    if let Some(meta) = find(&ip) {
+        return Some(meta);
+    }
+    if *UNKNOWN_PODS.read().unwrap() != UnknownPods::Lookup {
+        return None;
+    }
+
+    if let Some(meta) = LOOKUPS.get(&ip).await {
+        metrics::POD_LOOKUPS.with_label_values(&["cached"]).inc();
+        return meta;
+    }
+    match lookup(ip).await {
+        Ok(meta) => {
+            metrics::POD_LOOKUPS.with_label_values(&[if meta.is_some() { "found" } else { "missing" }]).inc();
+            LOOKUPS.insert(ip, meta.clone()).await;
+            meta
+        }
+        Err(e) => {
+            metrics::POD_LOOKUPS.with_label_values(&["error"]).inc();
+            warn!("failed to look up pod with IP {}: {}", ip, e);
+            None
+        }
+    }
+}
+
+async fn lookup(ip: IpAddr) -> Result<Option<Arc<PodMeta>>> {
+    let client = Client::try_default().await?;
+    let params = ListParams::default().fields(&format!("status.podIP={}", ip));
+    let pods = Api::<Pod>::all(client).list(&params).await?;
+    Ok(match pods.items.iter().find(|pod| is_running(pod)) {
+        Some(pod) => Some(Arc::new(PodMeta::resolve(pod).await)),
+        None => None,
+    })
+}
+
+pub fn find_by_name(namespace: &str, name: &str) -> Option<Arc<PodMeta>> {
+    POD_NAMES.get(&(namespace.to_string(), name.to_string()))
+        .map(|entry| Arc::clone(entry.value()))
+}
+
+/// Caches the pod under all of its IPs, replacing whatever was cached for it or for those IPs before.
+///
+/// Completed pods and pods sharing the node's network are dropped instead.
+pub async fn bind(pod: &Pod) {
+    if !is_running(pod) {
+        unbind(pod);
         return;
     }
 
-    let meta = PodMeta::from(pod);
-    if let Some(ip) = get_pod_ip(pod) {
-        if !PODMETAS.contains_key(&ip) {
-            info!("pod {:?} added with IP: {:?}", (&meta.namespace, &meta.name), &ip);
-            PODMETAS.insert(ip, Arc::new(meta));
+    let meta = Arc::new(PodMeta::resolve(pod).await);
+    let ips = pod_ips(pod);
+    let previous = POD_IPS.get(&meta.uid).map(|entry| entry.value().clone()).unwrap_or_default();
+    for ip in previous.iter().filter(|ip| !ips.contains(ip)) {
+        remove(ip, &meta.uid);
+    }
+
+    for ip in &ips {
+        match PODMETAS.get(ip) {
+            Some(entry) if entry.value().uid != meta.uid => {
+                let old = entry.value();
+                info!("IP {} moved from pod {}/{} to {}/{}", ip, old.namespace, old.name, meta.namespace, meta.name);
+            }
+            Some(_) => {}
+            None => info!("pod {:?} added with IP: {:?}", (&meta.namespace, &meta.name), ip),
         }
+        PODMETAS.insert(*ip, meta.clone());
+    }
+    let key = (meta.namespace.clone(), meta.name.clone());
+    if ips.is_empty() {
+        POD_IPS.remove(&meta.uid);
+        remove_name(&key, &meta.uid);
+    } else {
+        POD_IPS.insert(meta.uid.clone(), ips);
+        POD_NAMES.insert(key, meta.clone());
     }
+    metrics::POD_CACHE_SIZE.set(PODMETAS.len() as i64);
 }
 
-pub fn bind_all(pods: Vec<Pod>) {
-    PODMETAS.clear();
+/// Replaces the cache with the pods, without dropping pods that are still running in the meantime.
+pub async fn bind_all(pods: Vec<Pod>) {
+    let uids: HashSet<String> = pods.iter().filter_map(|pod| pod.uid()).collect();
     for pod in pods {
-        bind(&pod);
+        bind(&pod).await;
+    }
+
+    for entry in POD_IPS.iter().filter(|entry| !uids.contains(entry.key())) {
+        for ip in entry.value() {
+            remove(ip, entry.key());
+        }
+        entry.remove();
+    }
+    for entry in POD_NAMES.iter().filter(|entry| !uids.contains(&entry.value().uid)) {
+        entry.remove();
     }
+    metrics::POD_CACHE_SIZE.set(PODMETAS.len() as i64);
 }
 
 pub fn unbind(pod: &Pod) {
-    if let Some(ip) = get_pod_ip(pod) {
-        if let Some(entry) = PODMETAS.get(&ip) {
+    let uid = pod.uid().unwrap_or_default();
+    let mut ips = POD_IPS.remove(&uid).map(|entry| entry.value().clone()).unwrap_or_default();
+    ips.extend(pod_ips(pod));
+    for ip in &ips {
+        remove(ip, &uid);
+    }
+    remove_name(&(pod.namespace().unwrap_or_default(), pod.name_any()), &uid);
+    metrics::POD_CACHE_SIZE.set(PODMETAS.len() as i64);
+}
+
+/// Removes the cached pod of the IP, unless the IP was reused by another pod since.
+fn remove(ip: &IpAddr, uid: &str) {
+    if let Some(entry) = PODMETAS.get(ip) {
+        if entry.value().uid == uid {
             let meta = entry.value();
-            info!("pod {:?} deleted with IP: {:?}", (&meta.namespace, &meta.name), &ip);
+            info!("pod {:?} deleted with IP: {:?}", (&meta.namespace, &meta.name), ip);
+            entry.remove();
+        }
+    }
+}
+
+/// Removes the cached pod of the name, unless a pod of the same name was created since.
+fn remove_name(key: &(String, String), uid: &str) {
+    if let Some(entry) = POD_NAMES.get(key) {
+        if entry.value().uid == uid {
             entry.remove();
         }
     }
 }
 
-fn get_pod_ip(pod: &Pod) -> Option<String> {
-    let ip = pod.status.as_ref().and_then(|status| status.pod_ip.as_ref())?;
-    Some(ip.clone())
-}
\ No newline at end of file
+/// Whether the pod may still send traffic from its own IPs.
+/// Host-network pods never may, their IP is the node IP shared by every host-network pod of the node.
+fn is_running(pod: &Pod) -> bool {
+    let status = pod.status.as_ref();
+    let phase = status.and_then(|status| status.phase.as_deref());
+    let host_network = pod.spec.as_ref().and_then(|spec| spec.host_network).unwrap_or(false);
+    !host_network && !matches!(phase, Some("Succeeded") | Some("Failed"))
+}
+
+/// Every IP of the pod, `status.podIPs` of dual-stack pods included.
+fn pod_ips(pod: &Pod) -> Vec<IpAddr> {
+    let Some(status) = pod.status.as_ref() else {
+        return Vec::new();
+    };
+
+    let mut ips: Vec<IpAddr> = Vec::new();
+    let listed = status.pod_ips.iter().flatten().filter_map(|pod_ip| pod_ip.ip.as_ref());
+    for ip in status.pod_ip.iter().chain(listed) {
+        match ip.parse() {
+            Ok(ip) if !ips.contains(&ip) => ips.push(ip),
+            Ok(_) => {}
+            Err(_) => warn!("pod {}/{} has an invalid IP {}", pod.namespace().unwrap_or_default(), pod.name_any(), ip),
+        }
+    }
+    ips
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k8s_openapi::api::core::v1::{PodIP, PodStatus};
+    use kube::api::ObjectMeta;
+
+    fn pod(name: &str, uid: &str, ip: &str) -> Pod {
+        Pod {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                namespace: Some("index-test".to_string()),
+                uid: Some(uid.to_string()),
+                ..ObjectMeta::default()
+            },
+            status: Some(PodStatus {
+                pod_ips: Some(vec![PodIP { ip: Some(ip.to_string()) }]),
+                ..PodStatus::default()
+            }),
+            ..Pod::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn finds_pods_by_name() {
+        let old = pod("web-0", "uid-old", "10.250.0.1");
+        bind(&old).await;
+        assert_eq!(find_by_name("index-test", "web-0").unwrap().uid, "uid-old");
+
+        // a StatefulSet pod recreated under the same name, before the old one is deleted
+        let new = pod("web-0", "uid-new", "10.250.0.2");
+        bind(&new).await;
+        unbind(&old);
+        assert_eq!(find_by_name("index-test", "web-0").unwrap().uid, "uid-new");
+
+        unbind(&new);
+        assert!(find_by_name("index-test", "web-0").is_none());
+    }
+}
@@ -0,0 +1,41 @@
+use std::sync::Arc;
+use crossbeam_skiplist::SkipMap;
+use kube::ResourceExt;
+use lazy_static::lazy_static;
+use log::info;
+use crate::apis::proxy_policy::ProxyPolicy;
+
+lazy_static! {
+    static ref POLICIES: SkipMap<String, Arc<ProxyPolicy>> = SkipMap::new();
+}
+
+fn key(policy: &ProxyPolicy) -> String {
+    format!("{}/{}", policy.namespace().unwrap_or_default(), policy.name_any())
+}
+
+/// Every known policy, ordered by namespace and name.
+pub fn all() -> Vec<Arc<ProxyPolicy>> {
+    POLICIES.iter().map(|entry| Arc::clone(entry.value())).collect()
+}
+
+/// Returns true when any policy may apply to requests for the host.
+pub fn targets(host: &str) -> bool {
+    POLICIES.iter().any(|entry| entry.value().spec.targets(host))
+}
+
+pub fn bind(policy: ProxyPolicy) {
+    info!("policy {} loaded", key(&policy));
+    POLICIES.insert(key(&policy), Arc::new(policy));
+}
+
+pub fn bind_all(policies: Vec<ProxyPolicy>) {
+    POLICIES.clear();
+    for policy in policies {
+        bind(policy);
+    }
+}
+
+pub fn unbind(policy: &ProxyPolicy) {
+    info!("policy {} removed", key(policy));
+    POLICIES.remove(&key(policy));
+}
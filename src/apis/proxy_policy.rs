@@ -11,6 +11,7 @@ use anyhow::Result;
 use log::info;
 use schemars::gen::SchemaGenerator;
 use schemars::schema::Schema;
+use crate::config::hosts;
 
 const MESSAGE_KEY: &str = "data.proxy.message";
 const DEFAULT_MESSAGE: &str = "policy should contains message variable";
@@ -28,19 +29,55 @@ const POLICY_NAME: &str = "policy.rego";
     status = "ProxyPolicyStatus",
 )]
 pub struct ProxyPolicySpec {
+    /// host patterns the policy applies to, e.g. `github.com` or `*.corp.example`,
+    /// a policy without hosts applies to every host and forces every CONNECT to be intercepted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<String>,
     pub auth: ProxyPolicyAuth,
     pub rules: Vec<ProxyPolicyRule>,
 }
 
+impl ProxyPolicySpec {
+    pub fn targets(&self, host: &str) -> bool {
+        self.target(host).is_some()
+    }
+
+    /// The host pattern the policy applies to the host by, `*` for a policy without hosts.
+    pub fn target(&self, host: &str) -> Option<&str> {
+        if self.hosts.is_empty() {
+            return Some("*");
+        }
+        hosts::matching(&self.hosts, host)
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug, Default, JsonSchema)]
 pub struct ProxyPolicyAuth {
     pub method: ProxyPolicyMethod,
     pub secret: ProxyPolicySecret,
+    /// settings of the certificates minted by the `podCertificate` method
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub certificate: Option<PodCertificateSpec>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PodCertificateSpec {
+    /// lifetime of the minted certificates, e.g. `1h`, defaults to one hour
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub validity: Option<String>,
+    /// trust domain of the `spiffe://<trustDomain>/ns/<namespace>/sa/<serviceAccount>` SAN, defaults to `cluster.local`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trust_domain: Option<String>,
+    /// pod labels written to the subject as `OU=<label>=<value>`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
 }
 
-#[derive(Serialize, Deserialize,Debug, Clone, JsonSchema)]
+#[derive(Serialize, Deserialize,Debug, Clone, Default, JsonSchema)]
 pub enum ProxyPolicyMethod {
     #[serde(rename(deserialize = "basicAuth", serialize = "basicAuth"))]
+    #[default]
     BasicAuth,
     #[serde(rename(deserialize = "bearerToken", serialize = "bearerToken"))]
     BearerToken,
@@ -48,12 +85,12 @@ pub enum ProxyPolicyMethod {
     CustomHeader,
     #[serde(rename(deserialize = "query", serialize = "query"))]
     Query,
-}
-
-impl Default for ProxyPolicyMethod {
-    fn default() -> Self {
-        ProxyPolicyMethod::BasicAuth
-    }
+    /// presents the `tls.crt` and `tls.key` of a `kubernetes.io/tls` secret in the upstream handshake
+    #[serde(rename(deserialize = "clientCertificate", serialize = "clientCertificate"))]
+    ClientCertificate,
+    /// presents a short-lived certificate for the calling pod, signed by the CA in `tls.crt` and `tls.key` of the secret
+    #[serde(rename(deserialize = "podCertificate", serialize = "podCertificate"))]
+    PodCertificate,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
@@ -64,7 +101,7 @@ pub struct ProxyPolicySecret {
 
 impl JsonSchema for ProxyPolicySecret {
     fn schema_name() -> String {
-        return "ProxyPolicySecret".to_owned();
+        "ProxyPolicySecret".to_owned()
     }
 
     fn json_schema(_: &mut SchemaGenerator) -> Schema {
@@ -125,6 +162,27 @@ pub struct ProxyPolicyStatus {
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     #[schemars(schema_with = "conditions")]
     pub conditions: Vec<Condition>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub usage: Option<ProxyPolicyUsage>,
+}
+
+/// Usage of a policy summed over every proxy, since the proxies started collecting it.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct ProxyPolicyUsage {
+    /// requests every rule of the policy allowed
+    pub matches: u64,
+    /// requests the credentials were injected into
+    pub injections: u64,
+    /// requests a rule of the policy rejected
+    pub denials: u64,
+    /// policy evaluations or injections that failed
+    pub failures: u64,
+    /// time of the last injection, RFC 3339
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used: Option<String>,
+    /// number of distinct pods the credentials were injected for
+    pub distinct_pods: u64,
 }
 
 fn conditions(_: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
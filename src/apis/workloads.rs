@@ -0,0 +1,134 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::OnceLock;
+use std::time::Duration;
+use anyhow::Result;
+use futures::TryStreamExt;
+use k8s_openapi::NamespaceResourceScope;
+use k8s_openapi::api::apps::v1::ReplicaSet;
+use k8s_openapi::api::batch::v1::Job;
+use k8s_openapi::api::core::v1::Namespace;
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use kube::{Api, Client, Resource};
+use kube::core::PartialObjectMeta;
+use kube::runtime::{metadata_watcher, reflector, reflector::{ObjectRef, Store}, watcher};
+use lazy_static::lazy_static;
+use log::warn;
+use moka::future::Cache;
+use regorus::Value;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tokio::sync::OnceCell;
+use crate::admin;
+use crate::metrics;
+
+/// How long resolved workloads are cached.
+const CACHE_TTL: Duration = Duration::from_secs(300);
+const MAX_CACHED: u64 = 10_000;
+
+lazy_static! {
+    /// Workloads behind ReplicaSets and Jobs, by kind, namespace and name.
+    static ref WORKLOADS: Cache<(String, String, String), Owner> = Cache::builder()
+        .max_capacity(MAX_CACHED)
+        .time_to_live(CACHE_TTL)
+        .build();
+    /// Client the controllers are read with, built on the first lookup.
+    static ref CLIENT: OnceCell<Client> = OnceCell::new();
+}
+
+/// Metadata of every namespace, filled by `watch_namespaces`.
+static NAMESPACES: OnceLock<Store<PartialObjectMeta<Namespace>>> = OnceLock::new();
+
+/// The object controlling another one, as named by its controller owner reference.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Owner {
+    pub kind: String,
+    pub name: String,
+}
+
+impl Owner {
+    pub fn controller(meta: &ObjectMeta) -> Option<Owner> {
+        meta.owner_references.iter().flatten()
+            .find(|owner| owner.controller == Some(true))
+            .map(|owner| Owner { kind: owner.kind.clone(), name: owner.name.clone() })
+    }
+
+    pub fn as_input(&self) -> Value {
+        let mut input: BTreeMap<Value, Value> = BTreeMap::new();
+        input.insert(Value::from("kind"), Value::from(self.kind.as_str()));
+        input.insert(Value::from("name"), Value::from(self.name.as_str()));
+        Value::from(input)
+    }
+}
+
+/// The workload behind a controller, following ReplicaSets to their Deployment and Jobs to their CronJob.
+///
+/// Controllers are read on demand rather than watched, so only those of clients cost API calls and memory.
+/// The controller itself is returned when it cannot be read.
+pub async fn workload(namespace: &str, owner: &Owner) -> Owner {
+    let key = (owner.kind.clone(), namespace.to_string(), owner.name.clone());
+    if let Some(workload) = WORKLOADS.get(&key).await {
+        metrics::WORKLOAD_LOOKUPS.with_label_values(&["cached"]).inc();
+        return workload;
+    }
+
+    let result = match owner.kind.as_str() {
+        "ReplicaSet" => controller::<ReplicaSet>(namespace, &owner.name).await,
+        "Job" => controller::<Job>(namespace, &owner.name).await,
+        _ => return owner.clone(),
+    };
+    match result {
+        Ok(controller) => {
+            metrics::WORKLOAD_LOOKUPS.with_label_values(&["read"]).inc();
+            let workload = controller.unwrap_or_else(|| owner.clone());
+            WORKLOADS.insert(key, workload.clone()).await;
+            workload
+        }
+        Err(e) => {
+            metrics::WORKLOAD_LOOKUPS.with_label_values(&["error"]).inc();
+            warn!("failed to read {} {}/{}: {}", owner.kind, namespace, owner.name, e);
+            owner.clone()
+        }
+    }
+}
+
+/// Labels of the namespace, empty until the namespaces have been listed.
+pub fn namespace_labels(namespace: &str) -> BTreeMap<String, String> {
+    NAMESPACES.get()
+        .and_then(|store| store.get(&ObjectRef::new(namespace)))
+        .and_then(|object| object.metadata.labels.clone())
+        .unwrap_or_default()
+}
+
+/// Watches the metadata of every namespace into the store read by `namespace_labels`.
+pub async fn watch_namespaces() -> Result<(), watcher::Error> {
+    let client = Client::try_default().await.expect("Failed to init k8s client");
+    let api = Api::<Namespace>::all(client);
+    let (store, writer) = reflector::store();
+    if NAMESPACES.set(store).is_err() {
+        warn!("namespaces are already watched");
+        return Ok(());
+    }
+
+    reflector(writer, metadata_watcher(api, watcher::Config::default())).try_for_each(|event| async move {
+        if let watcher::Event::Restarted(_) = event {
+            metrics::WATCHER_RESTARTS.with_label_values(&["namespaces"]).inc();
+            admin::set_namespaces_synced();
+        }
+        Ok(())
+    }).await
+}
+
+/// Only the metadata is read, the controller is all that is kept of each object.
+async fn controller<K>(namespace: &str, name: &str) -> Result<Option<Owner>>
+where
+    K: Resource<DynamicType = (), Scope = NamespaceResourceScope> + Clone + DeserializeOwned + Debug,
+{
+    let api = Api::<K>::namespaced(client().await?, namespace);
+    let object = api.get_metadata_opt(name).await?;
+    Ok(object.and_then(|object| Owner::controller(&object.metadata)))
+}
+
+async fn client() -> Result<Client> {
+    Ok(CLIENT.get_or_try_init(Client::try_default).await?.clone())
+}
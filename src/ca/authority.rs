@@ -0,0 +1,152 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use hudsucker::certificate_authority::{CertificateAuthority, RcgenAuthority};
+use hudsucker::hyper::http::uri::Authority;
+use k8s_openapi::api::core::v1::Secret;
+use kube::{Api, Client, ResourceExt};
+use kube::runtime::{watcher, WatchStreamExt};
+use log::{error, info};
+use moka::future::Cache;
+use tokio::sync::oneshot;
+use tokio_rustls::rustls::ServerConfig;
+use crate::ca::{CaMaterial, CERT_KEY, KEY_KEY};
+use crate::metrics;
+
+/// Forged certificates are re-issued after this long, well before they expire.
+const CERT_CACHE_TTL: Duration = Duration::from_secs(24 * 3600);
+/// How long startup waits for the CA secret before giving up.
+const SECRET_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A certificate authority whose signing CA can be swapped while the proxy is running.
+///
+/// Forged certificates are cached here rather than in `RcgenAuthority`, so cache hits can be
+/// counted and a reload drops every certificate signed by the previous CA.
+/// Entries are stamped with the generation of the CA that signed them, so a certificate forged
+/// while a reload is in progress is never served after it.
+/// Cloning is cheap, every clone shares the same underlying authority.
+#[derive(Clone)]
+pub struct ReloadableAuthority {
+    inner: Arc<RwLock<(u64, Arc<RcgenAuthority>)>>,
+    cache: Cache<Authority, (u64, Arc<ServerConfig>)>,
+}
+
+impl ReloadableAuthority {
+    pub fn new(material: &CaMaterial, cache_size: u64) -> Result<Self> {
+        let authority = material.authority(0)?;
+        let cache = Cache::builder()
+            .max_capacity(cache_size)
+            .time_to_live(CERT_CACHE_TTL)
+            .build();
+
+        Ok(ReloadableAuthority {
+            inner: Arc::new(RwLock::new((0, Arc::new(authority)))),
+            cache,
+        })
+    }
+
+    pub fn reload(&self, material: &CaMaterial) -> Result<()> {
+        let authority = material.authority(0)?;
+        let mut inner = self.inner.write().unwrap();
+        *inner = (inner.0 + 1, Arc::new(authority));
+        drop(inner);
+        self.cache.invalidate_all();
+        Ok(())
+    }
+
+    fn current(&self) -> (u64, Arc<RcgenAuthority>) {
+        let inner = self.inner.read().unwrap();
+        (inner.0, Arc::clone(&inner.1))
+    }
+}
+
+impl CertificateAuthority for ReloadableAuthority {
+    async fn gen_server_config(&self, authority: &Authority) -> Arc<ServerConfig> {
+        let (generation, current) = self.current();
+        if let Some((signed_by, config)) = self.cache.get(authority).await {
+            if signed_by == generation {
+                metrics::CERT_CACHE.with_label_values(&["hit"]).inc();
+                return config;
+            }
+        }
+        metrics::CERT_CACHE.with_label_values(&["miss"]).inc();
+
+        let config = current.gen_server_config(authority).await;
+        self.cache.insert(authority.clone(), (generation, config.clone())).await;
+        config
+    }
+}
+
+/// Loads the signing CA from a Secret and keeps `ReloadableAuthority` in sync with it.
+///
+/// Resolves once the first usable CA has been read, the watch keeps running in the background.
+pub async fn watch_secret(namespace: &str, name: &str, cache_size: u64) -> Result<ReloadableAuthority> {
+    let client = Client::try_default().await?;
+    let api = Api::<Secret>::namespaced(client, namespace);
+    let config = watcher::Config::default().fields(&format!("metadata.name={}", name));
+
+    let (tx, rx) = oneshot::channel();
+    let watch = tokio::spawn(async move {
+        let mut tx = Some(tx);
+        let mut authority: Option<ReloadableAuthority> = None;
+        let mut current: Option<CaMaterial> = None;
+
+        let mut stream = watcher(api, config).applied_objects().boxed();
+        while let Some(event) = stream.next().await {
+            let secret = match event {
+                Ok(secret) => secret,
+                Err(e) => {
+                    error!("CA secret watch error: {}", e);
+                    continue;
+                }
+            };
+
+            let material = match secret.data.as_ref().and_then(|data| CaMaterial::from_data(data, KEY_KEY, CERT_KEY)) {
+                Some(Ok(material)) => material,
+                Some(Err(e)) => {
+                    error!("invalid CA in secret {}: {}", secret.name_any(), e);
+                    continue;
+                }
+                None => {
+                    error!("secret {} does not contain {} and {}", secret.name_any(), KEY_KEY, CERT_KEY);
+                    continue;
+                }
+            };
+
+            if current.as_ref() == Some(&material) {
+                continue;
+            }
+
+            let result = match authority.as_ref() {
+                Some(existing) => existing.reload(&material),
+                None => match ReloadableAuthority::new(&material, cache_size) {
+                    Ok(created) => {
+                        authority = Some(created);
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                },
+            };
+
+            match result {
+                Ok(_) => {
+                    info!("loaded CA from secret {}", secret.name_any());
+                    current = Some(material);
+                    if let (Some(tx), Some(authority)) = (tx.take(), authority.as_ref()) {
+                        let _ = tx.send(authority.clone());
+                    }
+                }
+                Err(e) => error!("failed to load CA from secret {}: {}", secret.name_any(), e),
+            }
+        }
+    });
+
+    match tokio::time::timeout(SECRET_TIMEOUT, rx).await {
+        Ok(authority) => Ok(authority?),
+        Err(_) => {
+            watch.abort();
+            Err(anyhow!("no valid CA found in secret {}/{} within {:?}", namespace, name, SECRET_TIMEOUT))
+        }
+    }
+}
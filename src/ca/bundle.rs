@@ -0,0 +1,157 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use futures::{stream, StreamExt};
+use k8s_openapi::api::core::v1::{ConfigMap, Namespace, Secret};
+use kube::{Api, Client, ResourceExt};
+use kube::api::{DeleteParams, ListParams, ObjectMeta, Patch, PatchParams};
+use kube::runtime::watcher;
+use log::{error, info};
+use crate::ca::{BUNDLE_KEY, CERT_KEY, SYSTEM_BUNDLE_KEY};
+use crate::metrics;
+
+const MANAGER: &str = "auth-bridge";
+const MANAGED_LABEL: &str = "auth-bridge.dev/ca-bundle";
+const RESYNC_INTERVAL: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Debug)]
+pub struct BundleConfig {
+    pub secret_namespace: String,
+    pub secret_name: String,
+    /// name of the ConfigMap created in every namespace
+    pub configmap_name: String,
+    /// label selector restricting the namespaces, all namespaces when empty
+    pub namespace_selector: Option<String>,
+    /// PEM system roots published together with the trust bundle under `SYSTEM_BUNDLE_KEY`
+    pub system_roots: String,
+}
+
+/// Publishes the CA trust bundle as a ConfigMap in every selected namespace.
+///
+/// Any change of the CA secret, the namespaces or the published ConfigMaps triggers a full sync,
+/// which only writes ConfigMaps whose content differs.
+pub async fn run(client: Client, config: BundleConfig) {
+    let secrets = Api::<Secret>::namespaced(client.clone(), &config.secret_namespace);
+    let secret_config = watcher::Config::default().fields(&format!("metadata.name={}", config.secret_name));
+
+    let namespaces = Api::<Namespace>::all(client.clone());
+    let mut namespace_config = watcher::Config::default();
+    if let Some(selector) = &config.namespace_selector {
+        namespace_config = namespace_config.labels(selector);
+    }
+
+    let configmaps = Api::<ConfigMap>::all(client.clone());
+    let configmap_config = watcher::Config::default().labels(&format!("{}=true", MANAGED_LABEL));
+
+    let resync = stream::unfold(tokio::time::interval(RESYNC_INTERVAL), |mut interval| async move {
+        interval.tick().await;
+        Some((Ok::<(), watcher::Error>(()), interval))
+    });
+
+    let mut triggers = stream::select_all(vec![
+        watcher(secrets, secret_config).map(|event| event.map(|_| ())).boxed(),
+        watcher(namespaces, namespace_config).map(|event| event.map(|_| ())).boxed(),
+        watcher(configmaps, configmap_config).map(|event| event.map(|_| ())).boxed(),
+        resync.boxed(),
+    ]);
+
+    while let Some(trigger) = triggers.next().await {
+        if let Err(e) = trigger {
+            error!("CA bundle watch error: {}", e);
+            continue;
+        }
+
+        let result = sync(&client, &config).await;
+        metrics::reconciled("ca-bundle", &result);
+        if let Err(e) = result {
+            error!("failed to sync CA bundle: {}", e);
+        }
+    }
+}
+
+async fn sync(client: &Client, config: &BundleConfig) -> Result<()> {
+    let secrets = Api::<Secret>::namespaced(client.clone(), &config.secret_namespace);
+    let secret = match secrets.get_opt(&config.secret_name).await? {
+        Some(secret) => secret,
+        // nothing to publish until the CA has been generated
+        None => return Ok(()),
+    };
+    let bundle = read_bundle(&secret)?;
+    let data = BTreeMap::from([
+        (BUNDLE_KEY.to_string(), bundle.clone()),
+        (SYSTEM_BUNDLE_KEY.to_string(), combine(&config.system_roots, &bundle)),
+    ]);
+
+    let mut params = ListParams::default();
+    if let Some(selector) = &config.namespace_selector {
+        params = params.labels(selector);
+    }
+    let namespaces: BTreeSet<String> = Api::<Namespace>::all(client.clone())
+        .list(&params)
+        .await?
+        .into_iter()
+        .filter(|ns| ns.metadata.deletion_timestamp.is_none())
+        .map(|ns| ns.name_any())
+        .collect();
+
+    let params = ListParams::default().labels(&format!("{}=true", MANAGED_LABEL));
+    let published: BTreeMap<String, ConfigMap> = Api::<ConfigMap>::all(client.clone())
+        .list(&params)
+        .await?
+        .into_iter()
+        .filter(|cm| cm.name_any() == config.configmap_name)
+        .filter_map(|cm| cm.namespace().map(|ns| (ns, cm)))
+        .collect();
+
+    for namespace in namespaces.iter() {
+        let current = published.get(namespace).and_then(|cm| cm.data.as_ref());
+        if current == Some(&data) {
+            continue;
+        }
+
+        let api = Api::<ConfigMap>::namespaced(client.clone(), namespace);
+        let configmap = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(config.configmap_name.clone()),
+                namespace: Some(namespace.clone()),
+                labels: Some(BTreeMap::from([(MANAGED_LABEL.to_string(), "true".to_string())])),
+                ..ObjectMeta::default()
+            },
+            data: Some(data.clone()),
+            ..ConfigMap::default()
+        };
+
+        match api.patch(&config.configmap_name, &PatchParams::apply(MANAGER).force(), &Patch::Apply(&configmap)).await {
+            Ok(_) => info!("published CA bundle to {}/{}", namespace, config.configmap_name),
+            Err(e) => error!("failed to publish CA bundle to {}/{}: {}", namespace, config.configmap_name, e),
+        }
+    }
+
+    for namespace in published.keys().filter(|ns| !namespaces.contains(*ns)) {
+        let api = Api::<ConfigMap>::namespaced(client.clone(), namespace);
+        match api.delete(&config.configmap_name, &DeleteParams::default()).await {
+            Ok(_) => info!("removed CA bundle from {}/{}", namespace, config.configmap_name),
+            Err(e) => error!("failed to remove CA bundle from {}/{}: {}", namespace, config.configmap_name, e),
+        }
+    }
+
+    Ok(())
+}
+
+fn read_bundle(secret: &Secret) -> Result<String> {
+    let data = secret.data.as_ref().ok_or(anyhow!("secret {} is empty", secret.name_any()))?;
+    let bundle = data.get(BUNDLE_KEY)
+        .or_else(|| data.get(CERT_KEY))
+        .ok_or(anyhow!("secret {} contains neither {} nor {}", secret.name_any(), BUNDLE_KEY, CERT_KEY))?;
+
+    Ok(String::from_utf8(bundle.0.clone())?)
+}
+
+fn combine(system_roots: &str, bundle: &str) -> String {
+    let mut combined = system_roots.trim_end().to_string();
+    if !combined.is_empty() {
+        combined.push('\n');
+    }
+    combined.push_str(bundle);
+    combined
+}
@@ -0,0 +1,66 @@
+use std::time::Duration;
+use anyhow::Result;
+use crossbeam_skiplist::SkipMap;
+use hudsucker::rcgen::{
+    CertificateParams, DnType, ExtendedKeyUsagePurpose, Ia5String, KeyUsagePurpose, SanType,
+};
+use lazy_static::lazy_static;
+use log::info;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use crate::apis::pod_meta::PodMeta;
+use crate::apis::proxy_policy::PodCertificateSpec;
+use crate::ca::{CaMaterial, IssuedCertificate};
+
+const DEFAULT_VALIDITY: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_TRUST_DOMAIN: &str = "cluster.local";
+
+lazy_static! {
+    /// Minted certificates by policy, issuing CA and subject, reused until two thirds of their lifetime passed.
+    static ref ISSUED: SkipMap<String, IssuedCertificate> = SkipMap::new();
+}
+
+/// Returns a short-lived client certificate for the pod, signed by `ca`.
+///
+/// The subject is `CN=<serviceAccount>, O=<namespace>` plus an `OU=<label>=<value>` per selected label,
+/// the URI SAN is `spiffe://<trustDomain>/ns/<namespace>/sa/<serviceAccount>`.
+pub fn pod_certificate(ca: &CaMaterial, pod: &PodMeta, spec: &PodCertificateSpec, policy: &str) -> Result<IssuedCertificate> {
+    let validity = match &spec.validity {
+        Some(validity) => humantime::parse_duration(validity)?,
+        None => DEFAULT_VALIDITY,
+    };
+    let trust_domain = spec.trust_domain.as_deref().unwrap_or(DEFAULT_TRUST_DOMAIN);
+    let uri = format!("spiffe://{}/ns/{}/sa/{}", trust_domain, pod.namespace, pod.service_account);
+    let units: Vec<String> = spec.labels.iter()
+        .filter_map(|label| pod.labels.get(label).map(|value| format!("{}={}", label, value)))
+        .collect();
+
+    let ca_fingerprint = format!("{:x}", Sha256::digest(&ca.cert_pem));
+    let key = format!("{}|{}|{}|{}|{:?}", policy, ca_fingerprint, uri, units.join(","), validity);
+    let renew_at = OffsetDateTime::now_utc() + time::Duration::try_from(validity / 3)?;
+    if let Some(entry) = ISSUED.get(&key) {
+        if entry.value().not_after > renew_at {
+            return Ok(entry.value().clone());
+        }
+    }
+
+    let mut params = CertificateParams::default();
+    params.distinguished_name.push(DnType::CommonName, pod.service_account.as_str());
+    params.distinguished_name.push(DnType::OrganizationName, pod.namespace.as_str());
+    for unit in &units {
+        params.distinguished_name.push(DnType::OrganizationalUnitName, unit.as_str());
+    }
+    params.subject_alt_names = vec![SanType::URI(Ia5String::try_from(uri.as_str())?)];
+    params.key_usages = vec![KeyUsagePurpose::DigitalSignature];
+    params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+
+    let issued = ca.issue(params, validity)?;
+    info!("issued client certificate {} for pod {}/{} expiring at {}", uri, pod.namespace, pod.name, issued.not_after);
+
+    let now = OffsetDateTime::now_utc();
+    for entry in ISSUED.iter().filter(|entry| entry.value().not_after < now) {
+        entry.remove();
+    }
+    ISSUED.insert(key, issued.clone());
+    Ok(issued)
+}
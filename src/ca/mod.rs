@@ -0,0 +1,167 @@
+pub mod authority;
+pub mod bundle;
+pub mod client;
+pub mod rotation;
+
+use std::collections::BTreeMap;
+use anyhow::{anyhow, Result};
+use hudsucker::certificate_authority::RcgenAuthority;
+use hudsucker::rcgen::{
+    BasicConstraints, CertificateParams, DistinguishedName, DnType, IsCa, KeyPair,
+    KeyUsagePurpose,
+};
+use k8s_openapi::ByteString;
+use time::{Duration, OffsetDateTime};
+
+/// Secret key holding the PEM encoded private key of the signing CA.
+pub const KEY_KEY: &str = "tls.key";
+/// Secret key holding the PEM encoded certificate of the signing CA.
+pub const CERT_KEY: &str = "tls.crt";
+/// Secret key holding the private key of a staged, not yet active CA.
+pub const NEXT_KEY_KEY: &str = "next.key";
+/// Secret key holding the certificate of a staged, not yet active CA.
+pub const NEXT_CERT_KEY: &str = "next.crt";
+/// Secret key holding the certificate of a retired CA that is still trusted.
+pub const PREVIOUS_CERT_KEY: &str = "previous.crt";
+/// Secret key holding every certificate clients should currently trust.
+pub const BUNDLE_KEY: &str = "ca.crt";
+/// ConfigMap key holding the system roots followed by the trust bundle, for clients reading a single CA file.
+pub const SYSTEM_BUNDLE_KEY: &str = "ca-certificates.crt";
+
+const COMMON_NAME: &str = "auth-bridge proxy CA";
+const ORGANIZATION: &str = "auth-bridge";
+
+#[derive(Clone, Copy, Debug, Default, clap::ValueEnum)]
+pub enum KeyAlgorithm {
+    #[default]
+    EcdsaP256,
+    EcdsaP384,
+    Ed25519,
+}
+
+impl KeyAlgorithm {
+    fn generate(&self) -> Result<KeyPair> {
+        let alg = match self {
+            KeyAlgorithm::EcdsaP256 => &hudsucker::rcgen::PKCS_ECDSA_P256_SHA256,
+            KeyAlgorithm::EcdsaP384 => &hudsucker::rcgen::PKCS_ECDSA_P384_SHA384,
+            KeyAlgorithm::Ed25519 => &hudsucker::rcgen::PKCS_ED25519,
+        };
+        Ok(KeyPair::generate_for(alg)?)
+    }
+}
+
+/// PEM encoded key and certificate of a CA, in the format `cmd::proxy::run` reads.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CaMaterial {
+    pub key_pem: String,
+    pub cert_pem: String,
+}
+
+impl CaMaterial {
+    pub fn generate(algorithm: KeyAlgorithm, validity: std::time::Duration) -> Result<Self> {
+        let key_pair = algorithm.generate()?;
+
+        let mut name = DistinguishedName::new();
+        name.push(DnType::CommonName, COMMON_NAME);
+        name.push(DnType::OrganizationName, ORGANIZATION);
+
+        let now = OffsetDateTime::now_utc();
+        let mut params = CertificateParams::default();
+        params.distinguished_name = name;
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+            KeyUsagePurpose::DigitalSignature,
+        ];
+        params.not_before = now - Duration::minutes(5);
+        params.not_after = now + Duration::try_from(validity)?;
+
+        let cert = params.self_signed(&key_pair)?;
+
+        Ok(CaMaterial {
+            key_pem: key_pair.serialize_pem(),
+            cert_pem: cert.pem(),
+        })
+    }
+
+    pub fn from_data(data: &BTreeMap<String, ByteString>, key: &str, cert: &str) -> Option<Result<Self>> {
+        let key_pem = data.get(key)?;
+        let cert_pem = data.get(cert)?;
+
+        Some(Self::from_bytes(&key_pem.0, &cert_pem.0))
+    }
+
+    pub fn from_bytes(key_pem: &[u8], cert_pem: &[u8]) -> Result<Self> {
+        Ok(CaMaterial {
+            key_pem: String::from_utf8(key_pem.to_vec())?,
+            cert_pem: String::from_utf8(cert_pem.to_vec())?,
+        })
+    }
+
+    pub fn not_after(&self) -> Result<OffsetDateTime> {
+        not_after(&self.cert_pem)
+    }
+
+    /// Signs a leaf certificate with this CA. The validity is capped at the CA's own expiry.
+    pub fn issue(&self, mut params: CertificateParams, validity: std::time::Duration) -> Result<IssuedCertificate> {
+        let ca_key = KeyPair::from_pem(self.key_pem.as_str())?;
+        let ca_params = CertificateParams::from_ca_cert_pem(self.cert_pem.as_str())?;
+        let ca_not_after = ca_params.not_after;
+        let ca_cert = ca_params.self_signed(&ca_key)?;
+
+        let now = OffsetDateTime::now_utc();
+        params.is_ca = IsCa::NoCa;
+        params.not_before = now - Duration::minutes(5);
+        let not_after = std::cmp::min(now + Duration::try_from(validity)?, ca_not_after);
+        params.not_after = not_after;
+
+        let key_pair = KeyPair::generate()?;
+        let cert = params.signed_by(&key_pair, &ca_cert, &ca_key)?;
+
+        Ok(IssuedCertificate {
+            cert_pem: cert.pem(),
+            key_pem: key_pair.serialize_pem(),
+            not_after,
+        })
+    }
+
+    pub fn authority(&self, cache_size: u64) -> Result<RcgenAuthority> {
+        let key_pair = KeyPair::from_pem(self.key_pem.as_str())
+            .map_err(|e| anyhow!("failed to parse CA private key: {}", e))?;
+        let ca_cert = CertificateParams::from_ca_cert_pem(self.cert_pem.as_str())
+            .map_err(|e| anyhow!("failed to parse CA certificate: {}", e))?
+            .self_signed(&key_pair)
+            .map_err(|e| anyhow!("failed to sign CA certificate: {}", e))?;
+
+        Ok(RcgenAuthority::new(key_pair, ca_cert, cache_size))
+    }
+}
+
+/// A leaf certificate signed by a `CaMaterial`.
+#[derive(Clone, Debug)]
+pub struct IssuedCertificate {
+    pub cert_pem: String,
+    pub key_pem: String,
+    pub not_after: OffsetDateTime,
+}
+
+pub fn not_after(cert_pem: &str) -> Result<OffsetDateTime> {
+    let params = CertificateParams::from_ca_cert_pem(cert_pem)?;
+    Ok(params.not_after)
+}
+
+/// Joins PEM certificates into a single trust bundle, skipping duplicates.
+pub fn bundle<'a>(certs: impl IntoIterator<Item = &'a str>) -> String {
+    let mut seen: Vec<&str> = Vec::new();
+    for cert in certs {
+        let cert = cert.trim();
+        if !cert.is_empty() && !seen.contains(&cert) {
+            seen.push(cert);
+        }
+    }
+
+    let mut bundle = seen.join("\n");
+    bundle.push('\n');
+    bundle
+}
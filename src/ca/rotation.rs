@@ -0,0 +1,288 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+use anyhow::{bail, Result};
+use k8s_openapi::api::core::v1::Secret;
+use k8s_openapi::ByteString;
+use kube::{Api, Client, ResourceExt};
+use kube::api::{ObjectMeta, PostParams};
+use log::{error, info};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+use crate::metrics;
+use crate::ca::{
+    bundle, CaMaterial, KeyAlgorithm,
+    BUNDLE_KEY, CERT_KEY, KEY_KEY, NEXT_CERT_KEY, NEXT_KEY_KEY, PREVIOUS_CERT_KEY,
+};
+
+const STAGED_AT_ANNOTATION: &str = "auth-bridge.dev/ca-staged-at";
+const PROMOTED_AT_ANNOTATION: &str = "auth-bridge.dev/ca-promoted-at";
+const SECRET_TYPE: &str = "kubernetes.io/tls";
+
+#[derive(Clone, Debug)]
+pub struct RotationConfig {
+    pub namespace: String,
+    pub name: String,
+    pub algorithm: KeyAlgorithm,
+    /// lifetime of every generated CA
+    pub validity: Duration,
+    /// how long before expiry a successor CA is staged
+    pub renew_before: Duration,
+    /// how long old and new CAs are trusted side by side
+    pub overlap: Duration,
+    pub interval: Duration,
+}
+
+impl RotationConfig {
+    /// Rejects durations under which a rotation could not finish before the active CA expires.
+    pub fn validate(&self) -> Result<()> {
+        if self.renew_before >= self.validity {
+            bail!("the CA renewal period {:?} must be shorter than its validity {:?}", self.renew_before, self.validity);
+        }
+        if self.overlap >= self.renew_before {
+            bail!("the CA overlap {:?} must be shorter than the renewal period {:?}", self.overlap, self.renew_before);
+        }
+        Ok(())
+    }
+}
+
+/// Keeps the CA Secret populated and rotates it before it expires.
+///
+/// A rotation happens in two steps, each lasting `overlap`: the successor is first staged
+/// and only trusted, then it becomes the signing CA while its predecessor stays trusted.
+pub async fn run(client: Client, config: RotationConfig) {
+    let api = Api::<Secret>::namespaced(client, &config.namespace);
+    let mut interval = tokio::time::interval(config.interval);
+
+    loop {
+        interval.tick().await;
+        let result = reconcile(&api, &config).await;
+        metrics::reconciled("ca-rotation", &result);
+        if let Err(e) = result {
+            error!("failed to reconcile CA secret {}/{}: {}", config.namespace, config.name, e);
+        }
+    }
+}
+
+async fn reconcile(api: &Api<Secret>, config: &RotationConfig) -> Result<()> {
+    let now = OffsetDateTime::now_utc();
+
+    match api.get_opt(&config.name).await? {
+        Some(mut secret) => {
+            let mut data = secret.data.take().unwrap_or_default();
+            let mut annotations = secret.annotations().clone();
+            if !rotate(&mut data, &mut annotations, config, now)? {
+                return Ok(());
+            }
+
+            secret.data = Some(data);
+            secret.metadata.annotations = Some(annotations);
+            // the resource version is kept, so concurrent controllers cannot overwrite each other
+            match api.replace(&config.name, &PostParams::default(), &secret).await {
+                Ok(_) => info!("rotated CA secret {}/{}", config.namespace, config.name),
+                Err(kube::Error::Api(err)) if err.code == 409 => {
+                    info!("CA secret {}/{} changed concurrently, retrying later", config.namespace, config.name);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        None => {
+            let mut data = BTreeMap::new();
+            let mut annotations = BTreeMap::new();
+            rotate(&mut data, &mut annotations, config, now)?;
+
+            let secret = Secret {
+                metadata: ObjectMeta {
+                    name: Some(config.name.clone()),
+                    namespace: Some(config.namespace.clone()),
+                    annotations: Some(annotations),
+                    ..ObjectMeta::default()
+                },
+                type_: Some(SECRET_TYPE.to_string()),
+                data: Some(data),
+                ..Secret::default()
+            };
+
+            match api.create(&PostParams::default(), &secret).await {
+                Ok(_) => info!("created CA secret {}/{}", config.namespace, config.name),
+                Err(kube::Error::Api(err)) if err.code == 409 => {
+                    info!("CA secret {}/{} created concurrently", config.namespace, config.name);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves the CA data one step forward, returns whether anything changed.
+fn rotate(
+    data: &mut BTreeMap<String, ByteString>,
+    annotations: &mut BTreeMap<String, String>,
+    config: &RotationConfig,
+    now: OffsetDateTime,
+) -> Result<bool> {
+    let mut changed = false;
+
+    let active = match CaMaterial::from_data(data, KEY_KEY, CERT_KEY).transpose()? {
+        Some(active) => active,
+        None => {
+            let active = CaMaterial::generate(config.algorithm, config.validity)?;
+            info!("generated CA expiring at {}", active.not_after()?);
+            set_material(data, KEY_KEY, CERT_KEY, &active);
+            changed = true;
+            active
+        }
+    };
+
+    match CaMaterial::from_data(data, NEXT_KEY_KEY, NEXT_CERT_KEY).transpose()? {
+        None => {
+            if now >= active.not_after()? - config.renew_before {
+                let next = CaMaterial::generate(config.algorithm, config.validity)?;
+                info!("staged successor CA expiring at {}", next.not_after()?);
+                set_material(data, NEXT_KEY_KEY, NEXT_CERT_KEY, &next);
+                set_time(annotations, STAGED_AT_ANNOTATION, now)?;
+                changed = true;
+            }
+        }
+        Some(next) => {
+            let staged_at = match get_time(annotations, STAGED_AT_ANNOTATION) {
+                Some(staged_at) => staged_at,
+                None => {
+                    set_time(annotations, STAGED_AT_ANNOTATION, now)?;
+                    changed = true;
+                    now
+                }
+            };
+
+            if now >= staged_at + config.overlap {
+                info!("promoted successor CA, previous CA stays trusted for {:?}", config.overlap);
+                data.insert(PREVIOUS_CERT_KEY.to_string(), ByteString(active.cert_pem.into_bytes()));
+                set_material(data, KEY_KEY, CERT_KEY, &next);
+                data.remove(NEXT_KEY_KEY);
+                data.remove(NEXT_CERT_KEY);
+                annotations.remove(STAGED_AT_ANNOTATION);
+                set_time(annotations, PROMOTED_AT_ANNOTATION, now)?;
+                changed = true;
+            }
+        }
+    }
+
+    if let Some(previous) = data.get(PREVIOUS_CERT_KEY) {
+        let expired = crate::ca::not_after(std::str::from_utf8(&previous.0)?)
+            .map(|not_after| now >= not_after)
+            .unwrap_or(true);
+        let retired = get_time(annotations, PROMOTED_AT_ANNOTATION)
+            .map(|promoted_at| now >= promoted_at + config.overlap)
+            .unwrap_or(true);
+
+        if expired || retired {
+            info!("dropped retired CA from the trust bundle");
+            data.remove(PREVIOUS_CERT_KEY);
+            annotations.remove(PROMOTED_AT_ANNOTATION);
+            changed = true;
+        }
+    }
+
+    let certs: Vec<String> = [CERT_KEY, NEXT_CERT_KEY, PREVIOUS_CERT_KEY]
+        .iter()
+        .filter_map(|key| data.get(*key))
+        .map(|cert| String::from_utf8_lossy(&cert.0).into_owned())
+        .collect();
+    let bundle = ByteString(bundle(certs.iter().map(String::as_str)).into_bytes());
+    if data.get(BUNDLE_KEY) != Some(&bundle) {
+        data.insert(BUNDLE_KEY.to_string(), bundle);
+        changed = true;
+    }
+
+    Ok(changed)
+}
+
+fn set_material(data: &mut BTreeMap<String, ByteString>, key: &str, cert: &str, material: &CaMaterial) {
+    data.insert(key.to_string(), ByteString(material.key_pem.clone().into_bytes()));
+    data.insert(cert.to_string(), ByteString(material.cert_pem.clone().into_bytes()));
+}
+
+fn get_time(annotations: &BTreeMap<String, String>, key: &str) -> Option<OffsetDateTime> {
+    annotations.get(key).and_then(|value| OffsetDateTime::parse(value, &Rfc3339).ok())
+}
+
+fn set_time(annotations: &mut BTreeMap<String, String>, key: &str, time: OffsetDateTime) -> Result<()> {
+    annotations.insert(key.to_string(), time.format(&Rfc3339)?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 3600);
+
+    fn config(validity: Duration, renew_before: Duration, overlap: Duration) -> RotationConfig {
+        RotationConfig {
+            namespace: "auth-bridge".to_string(),
+            name: "auth-bridge-ca".to_string(),
+            algorithm: KeyAlgorithm::EcdsaP256,
+            validity,
+            renew_before,
+            overlap,
+            interval: Duration::from_secs(60),
+        }
+    }
+
+    fn cert(data: &BTreeMap<String, ByteString>, key: &str) -> Option<String> {
+        data.get(key).map(|cert| String::from_utf8(cert.0.clone()).unwrap())
+    }
+
+    #[test]
+    fn validates_durations() {
+        let cases = [
+            (10 * DAY, 3 * DAY, DAY, true),
+            (10 * DAY, 3 * DAY, 3 * DAY, false),
+            (10 * DAY, 3 * DAY, 4 * DAY, false),
+            (10 * DAY, 10 * DAY, DAY, false),
+        ];
+        for (validity, renew_before, overlap, valid) in cases {
+            assert_eq!(config(validity, renew_before, overlap).validate().is_ok(), valid, "{:?} {:?} {:?}", validity, renew_before, overlap);
+        }
+    }
+
+    #[test]
+    fn stages_promotes_and_retires() {
+        let config = config(10 * DAY, 3 * DAY, DAY);
+        let mut data = BTreeMap::new();
+        let mut annotations = BTreeMap::new();
+        let now = OffsetDateTime::now_utc();
+
+        assert!(rotate(&mut data, &mut annotations, &config, now).unwrap());
+        let first = cert(&data, CERT_KEY).unwrap();
+        assert_eq!(cert(&data, BUNDLE_KEY).unwrap(), bundle([first.as_str()]));
+        assert!(!rotate(&mut data, &mut annotations, &config, now).unwrap());
+
+        // staged three days before expiry, trusted but not signing yet
+        let staged_at = now + time::Duration::days(7) + time::Duration::hours(1);
+        assert!(rotate(&mut data, &mut annotations, &config, staged_at).unwrap());
+        let next = cert(&data, NEXT_CERT_KEY).unwrap();
+        assert_eq!(cert(&data, CERT_KEY).unwrap(), first);
+        assert_eq!(cert(&data, BUNDLE_KEY).unwrap(), bundle([first.as_str(), next.as_str()]));
+        assert!(annotations.contains_key(STAGED_AT_ANNOTATION));
+        assert!(!rotate(&mut data, &mut annotations, &config, staged_at + time::Duration::hours(23)).unwrap());
+
+        // promoted after the overlap, the previous CA stays trusted
+        let promoted_at = staged_at + time::Duration::days(1);
+        assert!(rotate(&mut data, &mut annotations, &config, promoted_at).unwrap());
+        assert_eq!(cert(&data, CERT_KEY).unwrap(), next);
+        assert_eq!(cert(&data, PREVIOUS_CERT_KEY).unwrap(), first);
+        assert_eq!(cert(&data, NEXT_CERT_KEY), None);
+        assert_eq!(cert(&data, BUNDLE_KEY).unwrap(), bundle([next.as_str(), first.as_str()]));
+        assert!(!annotations.contains_key(STAGED_AT_ANNOTATION));
+        assert!(annotations.contains_key(PROMOTED_AT_ANNOTATION));
+
+        // retired after another overlap, the CAs are generated in real time so a successor of `next` is staged too
+        let retired_at = promoted_at + time::Duration::days(1);
+        assert!(rotate(&mut data, &mut annotations, &config, retired_at).unwrap());
+        assert_eq!(cert(&data, PREVIOUS_CERT_KEY), None);
+        assert!(!cert(&data, BUNDLE_KEY).unwrap().contains(first.trim()));
+        assert!(!annotations.contains_key(PROMOTED_AT_ANNOTATION));
+    }
+}
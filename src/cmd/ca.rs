@@ -0,0 +1,71 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use clap::Subcommand;
+use crate::ca::{CaMaterial, KeyAlgorithm};
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Generate a CA key and certificate in the format `proxy --ca-key/--ca-cert` expects
+    Generate(GenerateArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct GenerateArgs {
+    /// path the PKCS#8 PEM private key is written to
+    #[arg(long, default_value = "ca.key")]
+    key: String,
+
+    /// path the PEM certificate is written to
+    #[arg(long, default_value = "ca.cert")]
+    cert: String,
+
+    /// key algorithm of the CA
+    #[arg(long, value_enum, default_value_t)]
+    key_algorithm: KeyAlgorithm,
+
+    /// lifetime of the CA
+    #[arg(long, default_value = "8760h", value_parser = humantime::parse_duration)]
+    validity: Duration,
+
+    /// overwrite existing files
+    #[arg(long)]
+    force: bool,
+}
+
+pub fn run(command: &Commands) -> Result<()> {
+    match command {
+        Commands::Generate(args) => generate(args),
+    }
+}
+
+fn generate(args: &GenerateArgs) -> Result<()> {
+    for path in [&args.key, &args.cert] {
+        if !args.force && Path::new(path).exists() {
+            return Err(anyhow!("{} already exists, use --force to overwrite it", path));
+        }
+    }
+
+    let material = CaMaterial::generate(args.key_algorithm, args.validity)?;
+    write_private(&args.key, &material.key_pem)?;
+    fs::write(&args.cert, &material.cert_pem)?;
+
+    println!("wrote CA key to {} and certificate to {}, valid until {}", args.key, args.cert, material.not_after()?);
+    Ok(())
+}
+
+/// Writes the key readable by the owner only.
+fn write_private(path: &str, content: &str) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    options.open(path)?.write_all(content.as_bytes())?;
+    Ok(())
+}
@@ -1,42 +1,182 @@
-use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
 use kube::{
-    CustomResourceExt, Client, Api, ResourceExt,
-    api::PostParams,
+    Client, Api,
     runtime::{watcher, WatchStreamExt},
 };
-use log::{debug, error, info};
-use anyhow::Result;
+use log::{error, info};
+use anyhow::{anyhow, Result};
+use clap::Parser;
 use futures::stream::StreamExt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::time::Duration;
 use crate::apis::proxy_policy::ProxyPolicy;
+use crate::cmd::crd;
+use crate::ca::{KeyAlgorithm, bundle::{self, BundleConfig}, rotation::{self, RotationConfig}};
+use crate::leader::{self, LeaseConfig};
+use crate::metrics;
+use crate::stats::rollup;
+use crate::webhook::{self, WebhookConfig};
 
-pub async fn run() -> Result<()> {
+#[derive(Parser, Debug)]
+pub struct Args {
+    /// do not install or update the CRDs on startup, for clusters where they are managed by GitOps
+    #[arg(long)]
+    skip_crd_install: bool,
+
+    /// generate the proxy CA and rotate it before it expires
+    #[arg(long)]
+    manage_ca: bool,
+
+    /// namespace of the secret holding the proxy CA
+    #[arg(long, default_value = "auth-bridge")]
+    ca_secret_namespace: String,
+
+    /// name of the secret holding the proxy CA
+    #[arg(long, default_value = "auth-bridge-ca")]
+    ca_secret_name: String,
+
+    /// key algorithm of generated CAs
+    #[arg(long, value_enum, default_value_t)]
+    ca_key_algorithm: KeyAlgorithm,
+
+    /// lifetime of generated CAs
+    #[arg(long, default_value = "8760h", value_parser = humantime::parse_duration)]
+    ca_validity: Duration,
+
+    /// how long before expiry a new CA is generated
+    #[arg(long, default_value = "720h", value_parser = humantime::parse_duration)]
+    ca_renew_before: Duration,
+
+    /// how long the old and the new CA are both trusted during a rotation
+    #[arg(long, default_value = "168h", value_parser = humantime::parse_duration)]
+    ca_overlap: Duration,
+
+    /// publish the CA trust bundle as a ConfigMap in every selected namespace
+    #[arg(long)]
+    distribute_ca_bundle: bool,
+
+    /// name of the ConfigMap holding the CA trust bundle
+    #[arg(long, default_value = "auth-bridge-ca")]
+    ca_bundle_configmap: String,
+
+    /// label selector of the namespaces receiving the CA trust bundle, defaults to all namespaces
+    #[arg(long)]
+    ca_bundle_namespace_selector: Option<String>,
+
+    /// system roots published together with the CA in the trust bundle ConfigMap
+    #[arg(long, default_value = "/etc/ssl/certs/ca-certificates.crt")]
+    system_ca_file: PathBuf,
+
+    #[command(flatten)]
+    webhook: webhook::Args,
+
+    /// namespace the proxies publish usage statistics to
+    #[arg(long, default_value = "auth-bridge")]
+    stats_namespace: String,
+
+    /// how often usage statistics are rolled up into the policy status
+    #[arg(long, default_value = "60s", value_parser = humantime::parse_duration)]
+    stats_interval: Duration,
+
+    /// address of the listener serving /metrics
+    #[arg(long, default_value = "0.0.0.0:7751")]
+    metrics_addr: SocketAddr,
+
+    /// namespace of the lease electing the replica that runs the cluster-wide reconcilers
+    #[arg(long, default_value = "auth-bridge")]
+    leader_election_namespace: String,
+
+    /// name of the lease electing the replica that runs the cluster-wide reconcilers
+    #[arg(long, default_value = "auth-bridge-controller")]
+    leader_election_lease: String,
+
+    /// name of this replica in the lease, usually the pod name
+    #[arg(long, env = "POD_NAME")]
+    leader_election_identity: String,
+}
+
+pub async fn run(args: &Args) -> Result<()> {
     let client = Client::try_default().await?;
 
     // Manage CRDs first
-    let crd_api: Api<CustomResourceDefinition> = Api::all(client.clone());
+    if !args.skip_crd_install {
+        crd::install(client.clone()).await?;
+    }
+
+    let rotation = if args.manage_ca {
+        let config = RotationConfig {
+            namespace: args.ca_secret_namespace.clone(),
+            name: args.ca_secret_name.clone(),
+            algorithm: args.ca_key_algorithm,
+            validity: args.ca_validity,
+            renew_before: args.ca_renew_before,
+            overlap: args.ca_overlap,
+            interval: Duration::from_secs(60),
+        };
+        config.validate()?;
+        Some(config)
+    } else {
+        None
+    };
+
+    let bundle = if args.distribute_ca_bundle {
+        Some(BundleConfig {
+            secret_namespace: args.ca_secret_namespace.clone(),
+            secret_name: args.ca_secret_name.clone(),
+            configmap_name: args.ca_bundle_configmap.clone(),
+            namespace_selector: args.ca_bundle_namespace_selector.clone(),
+            system_roots: fs::read_to_string(&args.system_ca_file)
+                .map_err(|e| anyhow!("failed to read {}: {}", args.system_ca_file.display(), e))?,
+        })
+    } else {
+        None
+    };
 
-    let mut crd = ProxyPolicy::crd();
-    let params = PostParams::default();
+    let webhook = args.webhook.enabled.then(|| WebhookConfig {
+        args: args.webhook.clone(),
+        ca_secret_namespace: args.ca_secret_namespace.clone(),
+        ca_secret_name: args.ca_secret_name.clone(),
+        ca_configmap: args.ca_bundle_configmap.clone(),
+    });
 
-    match crd_api.get(&crd.metadata.name.as_ref().unwrap()).await {
-        Ok(old_crd) => {
-            crd.metadata.resource_version = old_crd.metadata.resource_version;
-            match crd_api.replace(&crd.metadata.name.as_ref().unwrap(), &params, &crd).await {
-                Ok(o) => info!("Updated CRD: {} ({:?})", o.name_any(), o.status.unwrap()),
-                Err(e) => error!("Failed to update CRD: {}", e),
+    // every replica serves the webhook and metrics
+    if let Some(config) = webhook.clone() {
+        let client = client.clone();
+        tokio::spawn(async move {
+            if let Err(e) = webhook::run(client, config).await {
+                error!("Failed to serve webhook: {}", e);
+                std::process::exit(1);
             }
+        });
+    }
+
+    let metrics_addr = args.metrics_addr;
+    tokio::spawn(async move {
+        if let Err(e) = metrics::serve(metrics_addr).await {
+            error!("Failed to serve metrics: {}", e);
+            std::process::exit(1);
         }
-        Err(kube::Error::Api(err_resp)) => if err_resp.code == 404 {
-            match crd_api.create(&params, &crd).await {
-                Ok(o) => {
-                    info!("Created {} ({:?})", o.name_any(), o.status.unwrap());
-                    debug!("Created CRD: {:?}", o.spec);
-                }
-                Err(e) => return Err(e.into()),
-            }
-        },
-        Err(e) => error!("Failed to retrieve existing CRD: {}", e),
+    });
+
+    // only the lease holder writes cluster-wide state
+    let lease = LeaseConfig {
+        namespace: args.leader_election_namespace.clone(),
+        name: args.leader_election_lease.clone(),
+        identity: args.leader_election_identity.clone(),
+    };
+    leader::acquire(client.clone(), lease).await?;
+
+    if let Some(config) = rotation {
+        tokio::spawn(rotation::run(client.clone(), config));
+    }
+    if let Some(config) = bundle {
+        tokio::spawn(bundle::run(client.clone(), config));
+    }
+    if let Some(config) = webhook {
+        tokio::spawn(webhook::sync_configuration(client.clone(), config));
     }
+    tokio::spawn(rollup::run(client.clone(), args.stats_namespace.clone(), args.stats_interval));
 
     let client = Client::try_default().await?;
     let api = Api::<ProxyPolicy>::default_namespaced(client);
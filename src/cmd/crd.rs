@@ -0,0 +1,66 @@
+use k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinition;
+use kube::{
+    CustomResourceExt, Client, Api, ResourceExt,
+    api::PostParams,
+};
+use log::{debug, error, info};
+use anyhow::Result;
+use clap::Subcommand;
+use crate::apis::proxy_policy::ProxyPolicy;
+
+#[derive(Subcommand, Debug)]
+pub enum Commands {
+    /// Print the CRDs as YAML, e.g. to commit them to a GitOps repository
+    Print,
+    /// Install or update the CRDs in the current cluster
+    Install,
+}
+
+/// Every custom resource definition served by auth-bridge.
+pub fn crds() -> Vec<CustomResourceDefinition> {
+    vec![ProxyPolicy::crd()]
+}
+
+pub async fn run(command: &Commands) -> Result<()> {
+    match command {
+        Commands::Print => {
+            for crd in crds() {
+                print!("---\n{}", serde_yaml::to_string(&crd)?);
+            }
+            Ok(())
+        }
+        Commands::Install => {
+            let client = Client::try_default().await?;
+            install(client).await
+        }
+    }
+}
+
+pub async fn install(client: Client) -> Result<()> {
+    let crd_api: Api<CustomResourceDefinition> = Api::all(client);
+    let params = PostParams::default();
+
+    for mut crd in crds() {
+        match crd_api.get(crd.metadata.name.as_ref().unwrap()).await {
+            Ok(old_crd) => {
+                crd.metadata.resource_version = old_crd.metadata.resource_version;
+                match crd_api.replace(crd.metadata.name.as_ref().unwrap(), &params, &crd).await {
+                    Ok(o) => info!("Updated CRD: {} ({:?})", o.name_any(), o.status.unwrap()),
+                    Err(e) => error!("Failed to update CRD: {}", e),
+                }
+            }
+            Err(kube::Error::Api(err_resp)) => if err_resp.code == 404 {
+                match crd_api.create(&params, &crd).await {
+                    Ok(o) => {
+                        info!("Created {} ({:?})", o.name_any(), o.status.unwrap());
+                        debug!("Created CRD: {:?}", o.spec);
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            },
+            Err(e) => error!("Failed to retrieve existing CRD: {}", e),
+        }
+    }
+
+    Ok(())
+}
@@ -1,2 +1,4 @@
+pub mod ca;
 pub mod controller;
+pub mod crd;
 pub mod proxy;
\ No newline at end of file
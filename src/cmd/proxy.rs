@@ -1,30 +1,37 @@
 use std::fs;
-use hudsucker::{certificate_authority::RcgenAuthority, Proxy};
-use hudsucker::rcgen::{CertificateParams, KeyPair};
-use std::net::SocketAddr;
+use std::sync::Arc;
+use hudsucker::Proxy;
+use hudsucker::builder::{ProxyBuilder, WantsClient};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
 use tokio::sync::Mutex;
 use clap::Parser;
 use k8s_openapi::api::core::v1::Pod;
 use kube::{Client, Api};
-use log::{error};
+use log::{error, info, warn};
+use tokio::net::TcpListener;
 use tokio::spawn;
-use crate::handlers::multi::{HandlerEnum, MultiHandler};
+use crate::config::{self, ProxyConfig};
+use crate::handlers::{multi::{HandlerEnum, MultiHandler}, websocket::WebSocketLogHandler};
 use lazy_static::lazy_static;
-use futures::TryStreamExt;
+use futures::{future::join_all, TryStreamExt};
 use kube::runtime::{watcher, watcher::Error};
-use anyhow::Result;
-use crate::apis::pod_meta;
+use anyhow::{anyhow, Result};
+use crate::admin;
+use crate::identity::{ClientAuthMode, token::{self, TokenAuth}};
+use crate::metrics;
+use crate::relay::{self, socks5, tls::{MtlsListenerConfig, TlsListenerConfig}, transparent::{self, TransparentMode}};
+use crate::apis::{pod_meta::{self, PodScope, UnknownPods}, policies, proxy_policy::ProxyPolicy, workloads};
+use crate::ca::{CaMaterial, authority::{self, ReloadableAuthority}};
+use crate::shutdown;
+use crate::stats;
+use crate::upstream::{ParentProxyConfig, Upstream, connector::{self, ParentProxy}, tls::{TrustConfig, UpstreamVerifier}};
 
 lazy_static! {
     static ref RESOURCE_VERSION: Mutex<String> = Mutex::new(String::new());
 }
 
-async fn shutdown_signal() {
-    tokio::signal::ctrl_c()
-        .await
-        .expect("Failed to install CTRL+C signal handler");
-}
-
 #[derive(Parser, Debug)]
 pub struct Args {
     /// path of the ca key
@@ -34,57 +41,443 @@ pub struct Args {
     /// path of the ca cert
     #[arg(long, default_value = "ca.cert")]
     ca_cert: String,
+
+    /// load the ca from a secret managed by the controller, as <namespace>/<name>,
+    /// takes precedence over --ca-key and --ca-cert and follows rotations without a restart
+    #[arg(long)]
+    ca_secret: Option<String>,
+
+    /// name of the node the proxy runs on, used to publish usage statistics
+    #[arg(long, env = "NODE_NAME")]
+    node_name: Option<String>,
+
+    /// namespace the usage statistics are published to
+    #[arg(long, default_value = "auth-bridge")]
+    stats_namespace: String,
+
+    /// how often usage statistics are published
+    #[arg(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    stats_interval: Duration,
+
+    /// address of a proxy listener, can be repeated, e.g. 0.0.0.0:7749 or [::]:7749 [default: 0.0.0.0:7749]
+    #[arg(long, value_delimiter = ',')]
+    listen: Vec<SocketAddr>,
+
+    /// address of a SOCKS5 listener feeding the same pipeline, can be repeated, e.g. 0.0.0.0:1080
+    #[arg(long, value_delimiter = ',')]
+    socks5_listen: Vec<SocketAddr>,
+
+    /// address of a listener accepting connections redirected by iptables or nftables, can be repeated
+    #[arg(long, value_delimiter = ',')]
+    transparent_listen: Vec<SocketAddr>,
+
+    /// how connections are redirected to the transparent listeners [default: redirect]
+    #[arg(long, value_enum)]
+    transparent_mode: Option<TransparentMode>,
+
+    /// address of a TLS listener for clients using `https://` proxy URLs, can be repeated, e.g. 0.0.0.0:7747
+    #[arg(long, value_delimiter = ',', requires = "tls_certificate_secret")]
+    tls_listen: Vec<SocketAddr>,
+
+    /// secret with the `tls.crt` and `tls.key` of the TLS listeners, as <namespace>/<name>, reloaded when it changes
+    #[arg(long)]
+    tls_certificate_secret: Option<String>,
+
+    /// address of a TLS listener requiring client certificates, can be repeated, e.g. 0.0.0.0:7748
+    #[arg(long, value_delimiter = ',', requires = "mtls_certificate_secret")]
+    mtls_listen: Vec<SocketAddr>,
+
+    /// secret with the `tls.crt` and `tls.key` of the mutual TLS listeners, as <namespace>/<name>
+    #[arg(long)]
+    mtls_certificate_secret: Option<String>,
+
+    /// PEM file with CAs client certificates have to be issued by
+    #[arg(long, value_delimiter = ',')]
+    mtls_client_ca_file: Vec<PathBuf>,
+
+    /// secret with CAs client certificates have to be issued by under `ca.crt`, as <namespace>/<name>
+    #[arg(long, value_delimiter = ',')]
+    mtls_client_ca_secret: Vec<String>,
+
+    /// SAN format client identities are read from, can be repeated
+    /// [default: spiffe://<--mtls-trust-domain>/ns/{namespace}/sa/{serviceAccount}]
+    #[arg(long)]
+    mtls_identity_format: Vec<String>,
+
+    /// trust domain of the SPIFFE IDs accepted by the default identity format, e.g. cluster.local
+    #[arg(long)]
+    mtls_trust_domain: Option<String>,
+
+    /// cache every pod of the cluster, or only the pods scheduled on --node-name [default: cluster]
+    #[arg(long, value_enum)]
+    pod_scope: Option<PodScope>,
+
+    /// identify clients missing from the pod cache by a cluster-wide lookup, or treat them as unknown [default: unknown]
+    #[arg(long, value_enum)]
+    unknown_pods: Option<UnknownPods>,
+
+    /// number of forged certificates kept in memory [default: 1000]
+    #[arg(long)]
+    cert_cache_size: Option<u64>,
+
+    /// handlers every request passes through, in order [default: log,policy]
+    #[arg(long, value_enum, value_delimiter = ',')]
+    handlers: Vec<HandlerEnum>,
+
+    /// host patterns whose CONNECT tunnels are never intercepted, e.g. *.apple.com
+    #[arg(long, value_delimiter = ',')]
+    bypass_hosts: Vec<String>,
+
+    /// whether clients authenticate with `Proxy-Authorization: Bearer <ServiceAccount token>` [default: disabled]
+    #[arg(long, value_enum)]
+    client_auth: Option<ClientAuthMode>,
+
+    /// audience ServiceAccount tokens have to be issued for [default: auth-bridge]
+    #[arg(long)]
+    token_audience: Option<String>,
+
+    /// how long the result of a token review is cached
+    #[arg(long, default_value = "2m", value_parser = humantime::parse_duration)]
+    token_cache_ttl: Duration,
+
+    /// log WebSocket messages, with credentials injected into the handshake redacted
+    #[arg(long)]
+    log_websocket_messages: bool,
+
+    /// YAML file with the settings above, flags take precedence over the file,
+    /// the handler chain is reloaded when the file changes
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// address of the admin listener serving /healthz, /readyz and /debug endpoints
+    #[arg(long, default_value = "0.0.0.0:7750")]
+    admin_addr: SocketAddr,
+
+    /// how long new connections are still accepted after SIGTERM while readiness reports false
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    shutdown_delay: Duration,
+
+    /// how long in-flight requests and tunnels may take to finish before they are closed
+    #[arg(long, default_value = "30s", value_parser = humantime::parse_duration)]
+    drain_timeout: Duration,
+
+    /// parent proxy upstream traffic is tunneled through with CONNECT, e.g. http://proxy.corp.example:3128
+    #[arg(long)]
+    upstream_proxy: Option<String>,
+
+    /// hosts, domain suffixes, IPs and CIDRs reached without the parent proxy
+    #[arg(long, value_delimiter = ',', requires = "upstream_proxy")]
+    upstream_no_proxy: Vec<String>,
+
+    /// secret with `username` and `password` for the parent proxy, as <namespace>/<name>
+    #[arg(long, requires = "upstream_proxy")]
+    upstream_proxy_secret: Option<String>,
+}
+
+impl Args {
+    fn overrides(&self) -> ProxyConfig {
+        ProxyConfig {
+            listen: self.listen.clone(),
+            socks5_listen: self.socks5_listen.clone(),
+            transparent_listen: self.transparent_listen.clone(),
+            transparent_mode: self.transparent_mode,
+            tls: (!self.tls_listen.is_empty()).then(|| TlsListenerConfig {
+                listen: self.tls_listen.clone(),
+                certificate_secret: self.tls_certificate_secret.clone().unwrap_or_default(),
+            }),
+            mtls: (!self.mtls_listen.is_empty()).then(|| MtlsListenerConfig {
+                listener: TlsListenerConfig {
+                    listen: self.mtls_listen.clone(),
+                    certificate_secret: self.mtls_certificate_secret.clone().unwrap_or_default(),
+                },
+                client_ca: TrustConfig {
+                    ca_files: self.mtls_client_ca_file.clone(),
+                    ca_secrets: self.mtls_client_ca_secret.clone(),
+                },
+                identity_formats: self.mtls_identity_format.clone(),
+                trust_domain: self.mtls_trust_domain.clone(),
+            }),
+            pod_scope: self.pod_scope,
+            unknown_pods: self.unknown_pods,
+            cert_cache_size: self.cert_cache_size,
+            handlers: self.handlers.clone(),
+            bypass_hosts: self.bypass_hosts.clone(),
+            client_auth: self.client_auth,
+            token_audience: self.token_audience.clone(),
+            log_websocket_messages: self.log_websocket_messages.then_some(true),
+            upstream_proxy: self.upstream_proxy.as_ref().map(|url| ParentProxyConfig {
+                url: url.clone(),
+                no_proxy: self.upstream_no_proxy.clone(),
+                credentials_secret: self.upstream_proxy_secret.clone(),
+            }),
+            upstream_tls: None,
+        }
+    }
 }
 
 pub async fn run(args: &Args) -> Result<()> {
-    let key_pair = fs::read_to_string(args.ca_key.clone())
-        .expect("Failed to read CA key file");
-    let ca_cert = fs::read_to_string(args.ca_cert.clone())
-        .expect("Failed to read CA cert file");
-    let key_pair = KeyPair::from_pem(key_pair.as_str()).expect("Failed to parse private key");
-    let ca_cert = CertificateParams::from_ca_cert_pem(ca_cert.as_str())
-        .expect("Failed to parse CA certificate")
-        .self_signed(&key_pair)
-        .expect("Failed to sign CA certificate");
+    let file = match &args.config {
+        Some(path) => ProxyConfig::load(path)?,
+        None => ProxyConfig::default(),
+    };
+    let settings = args.overrides().or(&file).or(&ProxyConfig::defaults());
+    let cert_cache_size = settings.cert_cache_size.unwrap_or_default();
 
+    let ca = match &args.ca_secret {
+        Some(secret) => {
+            let (namespace, name) = secret.split_once('/')
+                .ok_or(anyhow!("--ca-secret {} must be in the form <namespace>/<name>", secret))?;
+            authority::watch_secret(namespace, name, cert_cache_size).await?
+        }
+        None => {
+            let key_pair = fs::read_to_string(args.ca_key.clone())
+                .expect("Failed to read CA key file");
+            let ca_cert = fs::read_to_string(args.ca_cert.clone())
+                .expect("Failed to read CA cert file");
+            let material = CaMaterial { key_pem: key_pair, cert_pem: ca_cert };
+            ReloadableAuthority::new(&material, cert_cache_size).expect("Failed to load CA")
+        }
+    };
+
+    spawn(shutdown::run(args.shutdown_delay));
+
+    let pod_node = match settings.pod_scope.unwrap_or_default() {
+        PodScope::Cluster => None,
+        PodScope::Node => Some(args.node_name.clone().ok_or(anyhow!("--pod-scope=node requires --node-name"))?),
+    };
+    pod_meta::set_unknown_pods(settings.unknown_pods.unwrap_or_default());
     spawn(async move {
-        if let Err(error) = watch_pods().await {
-            error!("Failed to watch pods: {}", error);
+        tokio::select! {
+            result = watch_pods(pod_node) => if let Err(error) = result {
+                error!("Failed to watch pods: {}", error);
+                std::process::exit(1);
+            },
+            _ = shutdown::stopped() => info!("pod watcher stopped"),
+        }
+    });
+
+    spawn(async move {
+        tokio::select! {
+            result = workloads::watch_namespaces() => if let Err(error) = result {
+                error!("Failed to watch namespaces: {}", error);
+                std::process::exit(1);
+            },
+            _ = shutdown::stopped() => info!("namespace watcher stopped"),
+        }
+    });
+
+    spawn(async move {
+        tokio::select! {
+            result = watch_policies() => if let Err(error) = result {
+                error!("Failed to watch policies: {}", error);
+                std::process::exit(1);
+            },
+            _ = shutdown::stopped() => info!("policy watcher stopped"),
+        }
+    });
+
+    let admin_addr = args.admin_addr;
+    spawn(async move {
+        if let Err(error) = admin::serve(admin_addr).await {
+            error!("Failed to serve admin endpoints: {}", error);
             std::process::exit(1);
         }
     });
 
-    let ca = RcgenAuthority::new(key_pair, ca_cert, 1_000);
-    let handlers = vec!(
-        HandlerEnum::Log,
-        HandlerEnum::Policy
-    );
-    let handler = MultiHandler::new(handlers);
-    let proxy = Proxy::builder()
-        .with_addr(SocketAddr::from(([0, 0, 0, 0], 7749)))
-        .with_rustls_client()
-        .with_ca(ca)
-        .with_http_handler(handler)
-        .with_graceful_shutdown(shutdown_signal())
-        .build();
-
-    if let Err(e) = proxy.start().await {
-        error!("Failed to start proxy {}", e);
+    match &args.node_name {
+        Some(node) => {
+            let client = Client::try_default().await?;
+            spawn(stats::publish(client, args.stats_namespace.clone(), node.clone(), args.stats_interval));
+        }
+        None => warn!("--node-name is not set, usage statistics are not published"),
+    }
+
+    let parent = match &settings.upstream_proxy {
+        Some(config) => {
+            let parent = Arc::new(ParentProxy::new(&config.url, &config.no_proxy)?);
+            if let Some(secret) = &config.credentials_secret {
+                let (namespace, name) = secret.split_once('/')
+                    .ok_or(anyhow!("upstream proxy secret {} must be in the form <namespace>/<name>", secret))?;
+                let client = Client::try_default().await?;
+                spawn(connector::watch_credentials(client, namespace.to_string(), name.to_string(), parent.clone()));
+            }
+            info!("forwarding upstream traffic through {}", config.url);
+            Some(parent)
+        }
+        None => None,
+    };
+    let verifier = Arc::new(UpstreamVerifier::load(&settings.upstream_tls.clone().unwrap_or_default()).await?);
+    let upstream = Upstream::new(parent, verifier);
+    let client = upstream.client();
+    let websocket_connector = upstream.websocket_connector();
+
+    let handler = MultiHandler::new(settings.handlers.clone(), settings.bypass_hosts.clone(), upstream);
+    let websocket_handler = WebSocketLogHandler::new(settings.log_websocket_messages.unwrap_or_default());
+    let token_cache_ttl = args.token_cache_ttl;
+    token::configure(token_auth(&settings), token_cache_ttl);
+    if let Some(path) = &args.config {
+        let handler = handler.clone();
+        let websocket_handler = websocket_handler.clone();
+        spawn(config::watch(path.clone(), args.overrides(), settings.clone(), move |old, new| {
+            if old.handlers != new.handlers {
+                info!("handler chain changed to {:?}", new.handlers);
+                handler.set_handlers(new.handlers.clone());
+            }
+            if old.bypass_hosts != new.bypass_hosts {
+                info!("bypass hosts changed to {:?}", new.bypass_hosts);
+                handler.set_bypass(new.bypass_hosts.clone());
+            }
+            if old.client_auth != new.client_auth || old.token_audience != new.token_audience {
+                info!("client authentication changed to {:?}", new.client_auth);
+                token::configure(token_auth(new), token_cache_ttl);
+            }
+            if old.unknown_pods != new.unknown_pods {
+                info!("unknown pods are now handled as {:?}", new.unknown_pods);
+                pod_meta::set_unknown_pods(new.unknown_pods.unwrap_or_default());
+            }
+            if old.log_websocket_messages != new.log_websocket_messages {
+                websocket_handler.set_enabled(new.log_websocket_messages.unwrap_or_default());
+            }
+        }));
+    }
+
+    let mut listeners: Vec<(String, ProxyBuilder<WantsClient>)> = settings.listen.iter()
+        .map(|addr| (addr.to_string(), Proxy::builder().with_addr(*addr)))
+        .collect();
+
+    // SOCKS5, transparent and TLS connections are relayed into a proxy listener on loopback
+    let tls = settings.tls.clone().filter(|tls| !tls.listen.is_empty());
+    let mtls = settings.mtls.clone().filter(|mtls| !mtls.listener.listen.is_empty());
+    if !settings.socks5_listen.is_empty() || !settings.transparent_listen.is_empty() || tls.is_some() || mtls.is_some() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await?;
+        let addr = listener.local_addr()?;
+        relay::set_listener(addr);
+        listeners.push((format!("{} (relay)", addr), Proxy::builder().with_listener(listener)));
+    }
+    for addr in settings.socks5_listen.clone() {
+        spawn(async move {
+            if let Err(e) = socks5::serve(addr).await {
+                error!("Failed to serve SOCKS5 on {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        });
+    }
+    let transparent_mode = settings.transparent_mode.unwrap_or_default();
+    for addr in settings.transparent_listen.clone() {
+        spawn(async move {
+            if let Err(e) = transparent::serve(addr, transparent_mode).await {
+                error!("Failed to serve transparent proxy on {}: {}", addr, e);
+                std::process::exit(1);
+            }
+        });
+    }
+
+    if let Some(tls) = tls {
+        let resolver = relay::tls::watch_certificate(&tls.certificate_secret).await?;
+        let server_config = resolver.server_config();
+        for addr in tls.listen.clone() {
+            let server_config = server_config.clone();
+            spawn(async move {
+                if let Err(e) = relay::tls::serve(addr, server_config, "tls", None).await {
+                    error!("Failed to serve TLS on {}: {}", addr, e);
+                    std::process::exit(1);
+                }
+            });
+        }
+    }
+    if let Some(mtls) = mtls {
+        let resolver = relay::tls::watch_certificate(&mtls.listener.certificate_secret).await?;
+        let server_config = mtls.server_config(&resolver).await?;
+        let formats = Arc::new(mtls.identity_formats()?);
+        for addr in mtls.listener.listen.clone() {
+            let server_config = server_config.clone();
+            let formats = formats.clone();
+            spawn(async move {
+                if let Err(e) = relay::tls::serve(addr, server_config, "mtls", Some(formats)).await {
+                    error!("Failed to serve mutual TLS on {}: {}", addr, e);
+                    std::process::exit(1);
+                }
+            });
+        }
+    }
+
+    let proxies = listeners.into_iter().map(|(addr, builder)| {
+        let proxy = builder
+            .with_client(client.clone())
+            .with_ca(ca.clone())
+            .with_http_handler(handler.clone())
+            .with_websocket_handler(websocket_handler.clone())
+            .with_websocket_connector(websocket_connector.clone())
+            .with_graceful_shutdown(shutdown::stopped())
+            .build();
+
+        async move {
+            info!("proxy listening on {}", addr);
+            if let Err(e) = proxy.start().await {
+                error!("Failed to start proxy on {}: {}", addr, e);
+            }
+        }
+    });
+    let drain_timeout = args.drain_timeout;
+    tokio::select! {
+        _ = join_all(proxies) => info!("all connections drained"),
+        _ = async {
+            shutdown::stopped().await;
+            tokio::time::sleep(drain_timeout).await;
+        } => warn!("drain timeout of {:?} expired, closing remaining connections", drain_timeout),
     }
 
     Ok(())
 }
 
-async fn watch_pods() -> Result<(), Error> {
+fn token_auth(settings: &ProxyConfig) -> TokenAuth {
+    TokenAuth {
+        mode: settings.client_auth.unwrap_or_default(),
+        audience: settings.token_audience.clone().unwrap_or_default(),
+    }
+}
+
+/// Watches the pods of the cluster, or only those scheduled on `node`.
+async fn watch_pods(node: Option<String>) -> Result<(), Error> {
     let client = Client::try_default().await.expect("Failed to init k8s client");
     let api = Api::<Pod>::all(client);
-    let watcher = watcher(api, watcher::Config::default());
+    let config = match &node {
+        Some(node) => {
+            info!("watching pods on node {}", node);
+            watcher::Config::default().fields(&format!("spec.nodeName={}", node))
+        }
+        None => watcher::Config::default(),
+    };
+    let watcher = watcher(api, config);
 
     watcher.try_for_each(|event| async {
         match event {
-            watcher::Event::Applied(pod) => pod_meta::bind(&pod),
+            watcher::Event::Applied(pod) => pod_meta::bind(&pod).await,
             watcher::Event::Deleted(pod) => pod_meta::unbind(&pod),
-            watcher::Event::Restarted(pods) => pod_meta::bind_all(pods),
+            watcher::Event::Restarted(pods) => {
+                metrics::WATCHER_RESTARTS.with_label_values(&["pods"]).inc();
+                pod_meta::bind_all(pods).await;
+                admin::set_pods_synced();
+            }
+        }
+        Ok(())
+    }).await
+}
+
+async fn watch_policies() -> Result<(), Error> {
+    let client = Client::try_default().await.expect("Failed to init k8s client");
+    let api = Api::<ProxyPolicy>::all(client);
+    let watcher = watcher(api, watcher::Config::default());
+
+    watcher.try_for_each(|event| async {
+        match event {
+            watcher::Event::Applied(policy) => policies::bind(policy),
+            watcher::Event::Deleted(policy) => policies::unbind(&policy),
+            watcher::Event::Restarted(items) => {
+                metrics::WATCHER_RESTARTS.with_label_values(&["proxypolicies"]).inc();
+                policies::bind_all(items);
+                admin::set_policies_synced();
+            }
         }
         Ok(())
     }).await
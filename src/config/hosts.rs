@@ -0,0 +1,72 @@
+/// Matches a host against a pattern: `*` matches every host, `*.example.com` matches the
+/// subdomains of example.com, anything else must match exactly. Case and ports are ignored.
+pub fn matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.trim().to_lowercase();
+    let host = strip_port(host).to_lowercase();
+
+    match pattern.strip_prefix("*.") {
+        _ if pattern == "*" => true,
+        Some(domain) => host.ends_with(&format!(".{}", domain)),
+        None => host == pattern,
+    }
+}
+
+/// Returns true when any of the patterns matches the host.
+pub fn matches_any<S: AsRef<str>>(patterns: &[S], host: &str) -> bool {
+    matching(patterns, host).is_some()
+}
+
+/// Returns the first pattern matching the host.
+pub fn matching<'a, S: AsRef<str>>(patterns: &'a [S], host: &str) -> Option<&'a str> {
+    patterns.iter().map(AsRef::as_ref).find(|pattern| matches(pattern, host))
+}
+
+fn strip_port(host: &str) -> &str {
+    if let Some(rest) = host.strip_prefix('[') {
+        return rest.split(']').next().unwrap_or(rest);
+    }
+    match host.rsplit_once(':') {
+        Some((name, port)) if !name.contains(':') && port.parse::<u16>().is_ok() => name,
+        _ => host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_patterns() {
+        let cases = [
+            ("*", "example.com", true),
+            ("*", "example.com:443", true),
+            ("example.com", "example.com", true),
+            ("example.com", "EXAMPLE.com:443", true),
+            ("Example.COM ", "example.com", true),
+            ("example.com", "api.example.com", false),
+            ("example.com", "example.com.evil", false),
+            ("*.example.com", "api.example.com", true),
+            ("*.example.com", "a.b.example.com:8443", true),
+            ("*.example.com", "example.com", false),
+            ("*.example.com", "badexample.com", false),
+            ("10.0.0.1", "10.0.0.1:8080", true),
+            ("::1", "[::1]:443", true),
+            ("::1", "[::1]", true),
+            ("fe80::1", "fe80::1", true),
+            ("example.com", "example.com:notaport", false),
+        ];
+        for (pattern, host, expected) in cases {
+            assert_eq!(matches(pattern, host), expected, "{} against {}", pattern, host);
+        }
+    }
+
+    #[test]
+    fn returns_the_first_matching_pattern() {
+        let patterns = ["api.example.com", "*.example.com", "*"];
+        assert_eq!(matching(&patterns, "api.example.com:443"), Some("api.example.com"));
+        assert_eq!(matching(&patterns, "www.example.com"), Some("*.example.com"));
+        assert_eq!(matching(&patterns, "other.org"), Some("*"));
+        assert_eq!(matching(&patterns[..2], "other.org"), None);
+        assert!(!matches_any::<&str>(&[], "example.com"));
+    }
+}
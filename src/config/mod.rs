@@ -0,0 +1,237 @@
+pub mod hosts;
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use anyhow::Result;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use crate::apis::pod_meta::{PodScope, UnknownPods};
+use crate::handlers::multi::HandlerEnum;
+use crate::identity::ClientAuthMode;
+use crate::relay::{tls::{MtlsListenerConfig, TlsListenerConfig}, transparent::TransparentMode};
+use crate::upstream::{ParentProxyConfig, tls::UpstreamTlsConfig};
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Proxy settings read from the optional `--config` YAML file.
+///
+/// Every field is optional, flags given on the command line take precedence over the file.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ProxyConfig {
+    /// addresses of the proxy listeners, requires a restart
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub listen: Vec<SocketAddr>,
+    /// addresses of the SOCKS5 listeners, requires a restart
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub socks5_listen: Vec<SocketAddr>,
+    /// addresses of the listeners accepting redirected connections, requires a restart
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub transparent_listen: Vec<SocketAddr>,
+    /// how connections are redirected to the transparent listeners, requires a restart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transparent_mode: Option<TransparentMode>,
+    /// listener clients reach over TLS, using `https://` proxy URLs, requires a restart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls: Option<TlsListenerConfig>,
+    /// listener requiring client certificates that identify the client, requires a restart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mtls: Option<MtlsListenerConfig>,
+    /// whether every pod or only the pods of the node are cached, requires a restart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pod_scope: Option<PodScope>,
+    /// how clients missing from the pod cache are identified, reloaded at runtime
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub unknown_pods: Option<UnknownPods>,
+    /// number of forged certificates kept in memory, requires a restart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert_cache_size: Option<u64>,
+    /// handlers every request passes through, in order, reloaded at runtime
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub handlers: Vec<HandlerEnum>,
+    /// host patterns whose CONNECT tunnels are never intercepted, reloaded at runtime
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bypass_hosts: Vec<String>,
+    /// whether clients have to authenticate with a ServiceAccount token, reloaded at runtime
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_auth: Option<ClientAuthMode>,
+    /// audience ServiceAccount tokens have to be issued for, reloaded at runtime
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token_audience: Option<String>,
+    /// log WebSocket messages with injected credentials redacted, reloaded at runtime
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_websocket_messages: Option<bool>,
+    /// parent proxy upstream traffic is sent through, requires a restart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_proxy: Option<ParentProxyConfig>,
+    /// trusted CAs, pins and verification exceptions for upstream servers, requires a restart
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upstream_tls: Option<UpstreamTlsConfig>,
+}
+
+impl ProxyConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        Ok(serde_yaml::from_str(&content)?)
+    }
+
+    /// Fills every unset field from `other`.
+    pub fn or(mut self, other: &ProxyConfig) -> Self {
+        if self.listen.is_empty() {
+            self.listen = other.listen.clone();
+        }
+        if self.socks5_listen.is_empty() {
+            self.socks5_listen = other.socks5_listen.clone();
+        }
+        if self.transparent_listen.is_empty() {
+            self.transparent_listen = other.transparent_listen.clone();
+        }
+        if self.transparent_mode.is_none() {
+            self.transparent_mode = other.transparent_mode;
+        }
+        if self.tls.is_none() {
+            self.tls = other.tls.clone();
+        }
+        if self.mtls.is_none() {
+            self.mtls = other.mtls.clone();
+        }
+        if self.pod_scope.is_none() {
+            self.pod_scope = other.pod_scope;
+        }
+        if self.unknown_pods.is_none() {
+            self.unknown_pods = other.unknown_pods;
+        }
+        if self.cert_cache_size.is_none() {
+            self.cert_cache_size = other.cert_cache_size;
+        }
+        if self.handlers.is_empty() {
+            self.handlers = other.handlers.clone();
+        }
+        if self.bypass_hosts.is_empty() {
+            self.bypass_hosts = other.bypass_hosts.clone();
+        }
+        if self.client_auth.is_none() {
+            self.client_auth = other.client_auth;
+        }
+        if self.token_audience.is_none() {
+            self.token_audience = other.token_audience.clone();
+        }
+        if self.log_websocket_messages.is_none() {
+            self.log_websocket_messages = other.log_websocket_messages;
+        }
+        if self.upstream_proxy.is_none() {
+            self.upstream_proxy = other.upstream_proxy.clone();
+        }
+        if self.upstream_tls.is_none() {
+            self.upstream_tls = other.upstream_tls.clone();
+        }
+        self
+    }
+
+    pub fn defaults() -> Self {
+        ProxyConfig {
+            listen: vec![SocketAddr::from(([0, 0, 0, 0], 7749))],
+            socks5_listen: Vec::new(),
+            transparent_listen: Vec::new(),
+            transparent_mode: Some(TransparentMode::Redirect),
+            tls: None,
+            mtls: None,
+            pod_scope: Some(PodScope::Cluster),
+            unknown_pods: Some(UnknownPods::Unknown),
+            cert_cache_size: Some(1_000),
+            handlers: vec![HandlerEnum::Log, HandlerEnum::Policy],
+            bypass_hosts: Vec::new(),
+            client_auth: Some(ClientAuthMode::Disabled),
+            token_audience: Some("auth-bridge".to_string()),
+            log_websocket_messages: Some(false),
+            upstream_proxy: None,
+            upstream_tls: None,
+        }
+    }
+}
+
+/// Polls the config file and hands every changed, valid config to `apply`.
+///
+/// `overrides` holds the settings given on the command line, which keep precedence over the file.
+pub async fn watch<F>(path: PathBuf, overrides: ProxyConfig, mut current: ProxyConfig, apply: F)
+where
+    F: Fn(&ProxyConfig, &ProxyConfig) + Send + 'static,
+{
+    let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+    // the file is polled, an error is only logged when it differs from the previous one
+    let mut last_error: Option<String> = None;
+    loop {
+        interval.tick().await;
+
+        let config = match ProxyConfig::load(&path) {
+            Ok(config) => overrides.clone().or(&config).or(&ProxyConfig::defaults()),
+            Err(e) => {
+                let e = e.to_string();
+                if last_error.as_ref() != Some(&e) {
+                    error!("failed to reload config {}: {}", path.display(), e);
+                    last_error = Some(e);
+                }
+                continue;
+            }
+        };
+        if last_error.take().is_some() {
+            info!("config {} is valid again", path.display());
+        }
+        if config == current {
+            continue;
+        }
+
+        info!("config {} changed", path.display());
+        if config.listen != current.listen
+            || config.socks5_listen != current.socks5_listen
+            || config.transparent_listen != current.transparent_listen
+            || config.transparent_mode != current.transparent_mode
+            || config.tls != current.tls
+            || config.mtls != current.mtls
+            || config.pod_scope != current.pod_scope
+            || config.cert_cache_size != current.cert_cache_size
+            || config.upstream_proxy != current.upstream_proxy
+            || config.upstream_tls != current.upstream_tls
+        {
+            warn!("listener, podScope, certCacheSize, upstreamProxy and upstreamTls changes only take effect after a restart");
+        }
+        apply(&current, &config);
+        current = config;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(yaml: &str) -> ProxyConfig {
+        serde_yaml::from_str(yaml).unwrap()
+    }
+
+    #[test]
+    fn flags_take_precedence_over_the_file_and_defaults() {
+        let cases = [
+            // (flags, file, expected before defaults)
+            ("{}", "{}", "{}"),
+            ("listen: ['127.0.0.1:8080']", "listen: ['0.0.0.0:9090']", "listen: ['127.0.0.1:8080']"),
+            ("{}", "listen: ['0.0.0.0:9090']", "listen: ['0.0.0.0:9090']"),
+            ("clientAuth: required", "clientAuth: optional\ntokenAudience: files", "clientAuth: required\ntokenAudience: files"),
+            ("bypassHosts: []", "bypassHosts: ['*.internal']", "bypassHosts: ['*.internal']"),
+            ("certCacheSize: 10", "certCacheSize: 20\nunknownPods: lookup", "certCacheSize: 10\nunknownPods: lookup"),
+            ("logWebsocketMessages: false", "logWebsocketMessages: true", "logWebsocketMessages: false"),
+        ];
+        for (flags, file, expected) in cases {
+            let merged = parse(flags).or(&parse(file)).or(&ProxyConfig::defaults());
+            assert_eq!(merged, parse(expected).or(&ProxyConfig::defaults()), "flags {:?}, file {:?}", flags, file);
+        }
+    }
+
+    #[test]
+    fn defaults_fill_what_is_left_unset() {
+        let merged = ProxyConfig::default().or(&ProxyConfig::default()).or(&ProxyConfig::defaults());
+        assert_eq!(merged, ProxyConfig::defaults());
+        assert_eq!(merged.client_auth, Some(ClientAuthMode::Disabled));
+        assert_eq!(merged.listen, vec![SocketAddr::from(([0, 0, 0, 0], 7749))]);
+    }
+}
@@ -1,4 +1,5 @@
 pub mod log;
 pub mod multi;
 
-pub mod policy;
\ No newline at end of file
+pub mod policy;
+pub mod websocket;
\ No newline at end of file
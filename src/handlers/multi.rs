@@ -1,40 +1,251 @@
+use std::sync::{Arc, RwLock};
 use hudsucker::{Body, HttpContext, HttpHandler, RequestOrResponse};
-use hudsucker::hyper::Request;
+use hudsucker::hyper::{Method, Request, Response, StatusCode};
+use hyper_util::client::legacy::Error;
+use hyper_util::rt::TokioIo;
+use log::{debug, error};
+use tracing::{Instrument, Span};
+use serde::{Deserialize, Serialize};
+use crate::apis::policies;
+use crate::config::hosts;
+use crate::identity;
 use crate::handlers::log::LogHandler;
 use crate::handlers::policy::{PolicyHandler};
+use crate::handlers::websocket;
+use crate::metrics;
+use crate::telemetry::RequestTrace;
+use crate::upstream::{self, ClientCertificate, Upstream};
+use crate::upstream::connector::ParentProxy;
 
-#[derive(Clone,Default)]
+/// Clones share the handler chain and the bypass list, so `set_handlers` and `set_bypass`
+/// apply to every connection.
+///
+/// hudsucker clones the handler for every request, `trace` carries the spans of that request
+/// from `handle_request` to `handle_response`.
+///
+/// Requests a client certificate was injected into are forwarded by the handler itself, with the
+/// upstream client of that certificate, instead of hudsucker's shared client. WebSocket handshakes
+/// needing a certificate or the parent proxy are forwarded by the handler too, hudsucker would
+/// connect to the host directly and without the certificate.
+///
+/// CONNECTs that are not intercepted are tunneled by the handler itself when they have to go through
+/// the parent proxy, hudsucker would connect to the host directly.
+#[derive(Clone)]
 pub struct MultiHandler {
-    handlers: Vec<HandlerEnum>,
+    handlers: Arc<RwLock<Vec<HandlerEnum>>>,
+    bypass: Arc<RwLock<Vec<String>>>,
+    upstream: Upstream,
+    trace: Option<RequestTrace>,
 }
 
 impl MultiHandler {
-    pub fn new(handlers: Vec<HandlerEnum>) -> Self {
+    pub fn new(handlers: Vec<HandlerEnum>, bypass: Vec<String>, upstream: Upstream) -> Self {
         MultiHandler {
-            handlers
+            handlers: Arc::new(RwLock::new(handlers)),
+            bypass: Arc::new(RwLock::new(bypass)),
+            upstream,
+            trace: None,
         }
     }
+
+    pub fn set_handlers(&self, handlers: Vec<HandlerEnum>) {
+        *self.handlers.write().unwrap() = handlers;
+    }
+
+    pub fn set_bypass(&self, bypass: Vec<String>) {
+        *self.bypass.write().unwrap() = bypass;
+    }
+
+    fn intercepts(&self, host: &str) -> bool {
+        !hosts::matches_any(&self.bypass.read().unwrap(), host) && policies::targets(host)
+    }
 }
 
 impl HttpHandler for MultiHandler {
-    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
-        let mut result = RequestOrResponse::Request(req);
-
-        for handler in &self.handlers {
-            match result {
-                RequestOrResponse::Request(req) => {
-                    result = handler.handle_request(ctx, req).await;
-                },
-                RequestOrResponse::Response(_) => {
-                    break;
+    /// Only CONNECTs to hosts a policy may apply to are intercepted, the rest is tunneled untouched.
+    async fn should_intercept(&mut self, _ctx: &HttpContext, req: &Request<Body>) -> bool {
+        let host = req.uri().host().unwrap_or_default();
+        let intercept = self.intercepts(host);
+
+        let decision = if intercept { "intercepted" } else { "tunneled" };
+        metrics::CONNECTS.with_label_values(&[decision]).inc();
+        debug!("CONNECT to {} {}", host, decision);
+        intercept
+    }
+
+    async fn handle_request(&mut self, ctx: &HttpContext, mut req: Request<Body>) -> RequestOrResponse {
+        match identity::authenticate(ctx, &mut req).await {
+            Ok(Some(identity)) => {
+                req.extensions_mut().insert(identity);
+            }
+            Ok(None) => {}
+            Err(res) => return RequestOrResponse::Response(res),
+        }
+
+        if req.method() == Method::CONNECT {
+            let host = req.uri().host().unwrap_or_default().to_string();
+            if !self.intercepts(&host) {
+                if let Some(parent) = self.upstream.parent_for(&host) {
+                    metrics::CONNECTS.with_label_values(&["tunneled"]).inc();
+                    debug!("CONNECT to {} tunneled through the upstream proxy", host);
+                    return RequestOrResponse::Response(tunnel(req, &host, parent).await);
+                }
+            }
+        }
+
+        let trace = RequestTrace::start(&req);
+        let span = trace.as_ref().map(|trace| trace.request.clone()).unwrap_or_else(Span::none);
+
+        let handlers = self.handlers.read().unwrap().clone();
+
+        let mut result = async move {
+            let mut result = RequestOrResponse::Request(req);
+            for handler in &handlers {
+                match result {
+                    RequestOrResponse::Request(req) => {
+                        result = handler.handle_request(ctx, req).await;
+                    },
+                    RequestOrResponse::Response(_) => {
+                        break;
+                    }
+                }
+            }
+            result
+        }.instrument(span).await;
+
+        if let (RequestOrResponse::Request(req), Some(mut trace)) = (&mut result, trace) {
+            trace.forward(req.headers_mut());
+            self.trace = Some(trace);
+        }
+
+        match result {
+            RequestOrResponse::Request(req) if websocket::is_upgrade(req.headers()) => {
+                let host = req.uri().host().unwrap_or_default();
+                let forwarded = req.extensions().get::<ClientCertificate>().is_some()
+                    || self.upstream.parent_for(host).is_some();
+                if !forwarded {
+                    return RequestOrResponse::Request(req);
                 }
+                RequestOrResponse::Response(self.forward_upgrade(req).await)
             }
+            RequestOrResponse::Request(req) if req.extensions().get::<ClientCertificate>().is_some() => {
+                RequestOrResponse::Response(self.forward_with_certificate(req).await)
+            }
+            result => result,
         }
+    }
+
+    async fn handle_response(&mut self, _ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        self.finish(res)
+    }
 
-        result
+    async fn handle_error(&mut self, _ctx: &HttpContext, err: Error) -> Response<Body> {
+        self.fail(err.to_string())
     }
 }
-#[derive(Clone)]
+
+impl MultiHandler {
+    async fn forward_with_certificate(&mut self, mut req: Request<Body>) -> Response<Body> {
+        let certificate = match req.extensions_mut().remove::<ClientCertificate>() {
+            Some(certificate) => certificate,
+            None => return self.fail("client certificate missing".to_string()),
+        };
+        let client = match self.upstream.client_with_certificate(&certificate).await {
+            Ok(client) => client,
+            Err(e) => return self.fail(format!("invalid client certificate: {}", e)),
+        };
+
+        match client.request(upstream::normalize(req)).await {
+            Ok(res) => self.finish(res.map(Body::from)),
+            Err(e) => self.fail(e.to_string()),
+        }
+    }
+
+    /// Forwards a WebSocket handshake with the upstream client and splices both upgraded connections,
+    /// messages are passed through without the WebSocket handler.
+    async fn forward_upgrade(&mut self, mut req: Request<Body>) -> Response<Body> {
+        let certificate = req.extensions_mut().remove::<ClientCertificate>();
+        let client = match self.upstream.upgrade_client(certificate.as_ref()) {
+            Ok(client) => client,
+            Err(e) => return self.fail(format!("invalid client certificate: {}", e)),
+        };
+
+        let uri = req.uri().clone();
+        let downstream = hyper::upgrade::on(&mut req);
+        let mut res = match client.request(upstream::normalize(req)).await {
+            Ok(res) => res,
+            Err(e) => return self.fail(e.to_string()),
+        };
+
+        if res.status() == StatusCode::SWITCHING_PROTOCOLS {
+            let upstream = hyper::upgrade::on(&mut res);
+            tokio::spawn(async move {
+                match tokio::try_join!(downstream, upstream) {
+                    Ok((downstream, upstream)) => {
+                        let result = tokio::io::copy_bidirectional(&mut TokioIo::new(downstream), &mut TokioIo::new(upstream)).await;
+                        if let Err(e) = result {
+                            debug!("WebSocket to {} closed: {}", uri, e);
+                        }
+                    }
+                    Err(e) => error!("failed to upgrade WebSocket to {}: {}", uri, e),
+                }
+            });
+        }
+        self.finish(res.map(Body::from))
+    }
+
+    fn finish(&mut self, res: Response<Body>) -> Response<Body> {
+        metrics::UPSTREAM_RESPONSES.with_label_values(&[res.status().as_str()]).inc();
+        if let Some(trace) = self.trace.take() {
+            trace.finish(Some(res.status()));
+        }
+        res
+    }
+
+    fn fail(&mut self, err: String) -> Response<Body> {
+        metrics::UPSTREAM_RESPONSES.with_label_values(&["error"]).inc();
+        if let Some(trace) = self.trace.take() {
+            trace.finish(None);
+        }
+        error!("Failed to forward request: {}", err);
+
+        let mut res = Response::new(Body::empty());
+        *res.status_mut() = StatusCode::BAD_GATEWAY;
+        res
+    }
+}
+/// Opens the tunnel through the parent proxy, then splices the upgraded client connection into it.
+async fn tunnel(mut req: Request<Body>, host: &str, parent: Arc<ParentProxy>) -> Response<Body> {
+    let port = req.uri().port_u16().unwrap_or(443);
+    let mut upstream = match parent.tunnel(host, port).await {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            error!("failed to tunnel CONNECT to {}:{} through the upstream proxy: {}", host, port, e);
+            let mut res = Response::new(Body::empty());
+            *res.status_mut() = StatusCode::BAD_GATEWAY;
+            return res;
+        }
+    };
+
+    let authority = format!("{}:{}", host, port);
+    tokio::spawn(async move {
+        let upgraded = match hyper::upgrade::on(&mut req).await {
+            Ok(upgraded) => upgraded,
+            Err(e) => {
+                error!("failed to upgrade CONNECT to {}: {}", authority, e);
+                return;
+            }
+        };
+        if let Err(e) = tokio::io::copy_bidirectional(&mut TokioIo::new(upgraded), &mut upstream).await {
+            debug!("tunnel to {} closed: {}", authority, e);
+        }
+    });
+
+    Response::new(Body::empty())
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
 pub enum HandlerEnum {
     Log,
     Policy
@@ -49,4 +260,4 @@ impl HandlerEnum {
 
         res
     }
-}
\ No newline at end of file
+}
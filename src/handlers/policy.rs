@@ -1,4 +1,5 @@
 use std::collections::{BTreeMap};
+use std::sync::Arc;
 use http_body_util::{Collected, Full};
 use hudsucker::{Body, HttpContext, HttpHandler, RequestOrResponse};
 use hudsucker::hyper::{Request, Response, StatusCode};
@@ -7,13 +8,25 @@ use bytes::Bytes;
 use hudsucker::tokio_tungstenite::tungstenite::http::Method;
 use hyper::http::request::Parts;
 use regorus::Value;
+use crate::admin::decisions::{self, Decision, Outcome};
 use crate::apis::{
     pod_meta,
+    policies,
     proxy_policy::ProxyPolicy,
 };
-use kube::{api::{Api, ListParams}, Client, ResourceExt};
+use kube::ResourceExt;
 use log::{error, info};
+use tracing::info_span;
 use crate::secret::injector::{inject};
+use crate::handlers::websocket;
+use crate::identity::Identity;
+use crate::metrics;
+use crate::relay;
+use crate::stats::{self, Event};
+
+const FORM: &str = "application/x-www-form-urlencoded";
+const JSON: &str = "application/json";
+const GRPC: &str = "application/grpc";
 
 #[derive(Clone, Default)]
 pub struct PolicyHandler;
@@ -27,15 +40,24 @@ impl HttpHandler for PolicyHandler {
         info!("request url: {}",req.uri().to_string());
 
         let (parts, body) = req.into_parts();
-        let bytes = match http_body_util::BodyExt::collect(body).await.map(Collected::to_bytes) {
-            Ok(bytes) => bytes,
-            Err(err) => {
-                return handle_parse_error(err)
+        let content_type = parts.headers.get(hyper::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or_default()
+            .to_string();
+
+        // only bodies the rules can see are buffered, streams such as gRPC calls pass through untouched
+        let (body_clone, bytes) = if content_type.starts_with(FORM) || content_type.starts_with(JSON) {
+            match http_body_util::BodyExt::collect(body).await.map(Collected::to_bytes) {
+                Ok(bytes) => (Body::from(Full::from(bytes.clone())), bytes),
+                Err(err) => {
+                    return handle_parse_error(err)
+                }
             }
+        } else {
+            (body, Bytes::new())
         };
 
         let parts_clone = parts.clone();
-        let body_clone = Body::from(Full::from(bytes.clone()));
 
         let query = match parse_query(parts).await {
             Ok(query) => query,
@@ -44,12 +66,8 @@ impl HttpHandler for PolicyHandler {
             }
         };
 
-
-        let content_type = parts_clone.headers.get(hyper::header::CONTENT_TYPE)
-            .and_then(|value| value.to_str().ok());
-
-        let body = match content_type {
-            Some(t) if t.starts_with("application/x-www-form-urlencoded") => {
+        let body = match content_type.as_str() {
+            t if t.starts_with(FORM) => {
                 match parse_form(bytes).await {
                     Ok(body) => body,
                     Err(err) => {
@@ -57,7 +75,7 @@ impl HttpHandler for PolicyHandler {
                     }
                 }
             }
-            Some(t) if t.starts_with("application/json") => {
+            t if t.starts_with(JSON) => {
                 match parse_json(bytes).await {
                     Ok(form) => form,
                     Err(err) => {
@@ -68,11 +86,7 @@ impl HttpHandler for PolicyHandler {
             _ => Value::new_object(),
         };
 
-        let client = Client::try_default().await.map_err(handle_api_error).unwrap();
-        let api = Api::<ProxyPolicy>::all(client);
-
-        let params = ListParams::default();
-        let policies = api.list(&params).await.map_err(handle_api_error).unwrap();
+        let policies = policies::all();
 
         let mut input: BTreeMap<Value, Value> = BTreeMap::new();
         input.insert(Value::from("query"), query);
@@ -81,41 +95,111 @@ impl HttpHandler for PolicyHandler {
         let uri = parts_clone.uri.to_string();
         input.insert(Value::from("uri"), Value::from(uri));
 
-        let ip = ctx.client_addr.ip();
-        if let Some(meta) = pod_meta::find(&ip.to_string()) {
+        let websocket = websocket::is_upgrade(&parts_clone.headers);
+        input.insert(Value::from("websocket"), Value::from(websocket));
+
+        if content_type.starts_with(GRPC) {
+            if let Some(grpc) = parse_grpc(&parts_clone) {
+                input.insert(Value::from("grpc"), grpc);
+            }
+        }
+
+        let ip = relay::client_addr(ctx.client_addr).ip();
+        let identity = parts_clone.extensions.get::<Identity>().cloned();
+        let meta = match &identity {
+            Some(identity) => {
+                input.insert(Value::from("identity"), identity.as_input());
+                Some(Arc::new(identity.meta(ip).await))
+            }
+            None => pod_meta::resolve(ip).await,
+        };
+        let mut pod = None;
+        if let Some(meta) = &meta {
             input.insert(Value::from("meta"), meta.as_input());
+            pod = Some(format!("{}/{}", meta.namespace, meta.name));
+        } else {
+            metrics::UNKNOWN_CLIENTS.inc();
         }
+        let pod_or_ip = pod.clone().unwrap_or_else(|| ip.to_string());
+
+        let method = parts_clone.method.to_string();
+        let original_uri = parts_clone.uri.clone();
+        let host = request_host(&parts_clone);
+        let report = |policy: &str, target: &str, outcome: Outcome, err: Option<String>| {
+            let (event, label) = match outcome {
+                Outcome::Injected => (Event::Injected, "injected"),
+                Outcome::Denied => (Event::Denied, "denied"),
+                Outcome::Failed => (Event::Failed, "error"),
+            };
+            stats::record(policy, event, Some(&pod_or_ip));
+            metrics::REQUESTS.with_label_values(&[policy, target, label]).inc();
 
+            let decision = Decision::new(ip.to_string(), pod.clone(), &method, &original_uri, policy.to_string(), outcome);
+            decisions::record(match err {
+                Some(err) => decision.with_error(err),
+                None => decision,
+            });
+        };
+
+        let mut injected = false;
         let mut req_clone = Request::from_parts(parts_clone, body_clone);
-        for item in policies.iter() {
-            match eval_policy(&item, &input) {
+        if let Some(meta) = &meta {
+            req_clone.extensions_mut().insert(meta.clone());
+        }
+        for (item, target) in policies.iter().filter_map(|item| item.spec.target(&host).map(|target| (item, target))) {
+            let key = stats::policy_key(&item.namespace().unwrap_or_default(), &item.name_any());
+            let timer = metrics::POLICY_EVALUATION.with_label_values(&[&key]).start_timer();
+            let result = info_span!("policy.evaluate", policy = %key).in_scope(|| eval_policy(item, &input));
+            timer.observe_duration();
+
+            match result {
                 Ok(allow) => {
                     info!("proxy eval: {}, result: {}", item.name_any(),allow);
 
                     if !allow {
+                        report(&key, target, Outcome::Denied, None);
                         continue;
                     }
+                    stats::record(&key, Event::Matched, None);
 
                     match inject(&mut req_clone, item).await {
-                        Ok(_) => {
+                        Ok(values) => {
+                            if websocket {
+                                websocket::remember(ctx.client_addr, values);
+                            }
                             info!("inject auth by policy: {}", item.name_any());
+                            injected = true;
+                            report(&key, target, Outcome::Injected, None);
                         }
                         Err(err) => {
                             error!("failed to inject auth: {}, err: {}", item.name_any(), err);
+                            report(&key, target, Outcome::Failed, Some(err.to_string()));
                         }
                     }
                 }
                 Err(err) => {
                     error!("failed to eval policy: {}, err: {}", item.name_any(), err);
+                    report(&key, target, Outcome::Failed, Some(err.to_string()));
                     continue;
                 }
             }
         }
 
+        if !injected {
+            metrics::REQUESTS.with_label_values(&["", "", "skipped"]).inc();
+        }
+
         RequestOrResponse::Request(req_clone)
     }
 }
 
+fn request_host(parts: &Parts) -> String {
+    parts.uri.host()
+        .or_else(|| parts.headers.get(hyper::header::HOST).and_then(|value| value.to_str().ok()))
+        .unwrap_or_default()
+        .to_string()
+}
+
 fn eval_policy(policy: &ProxyPolicy, input: &BTreeMap<Value, Value>) -> Result<bool> {
     for rule in policy.spec.rules.iter() {
         if !rule.eval(input)? {
@@ -141,6 +225,16 @@ async fn parse_form(bytes: Bytes<>) -> Result<Value> {
     Ok(v)
 }
 
+/// Splits the `/<package>.<Service>/<Method>` path of a gRPC call into its service and method.
+fn parse_grpc(parts: &Parts) -> Option<Value> {
+    let (service, method) = parts.uri.path().trim_start_matches('/').split_once('/')?;
+
+    let mut grpc: BTreeMap<Value, Value> = BTreeMap::new();
+    grpc.insert(Value::from("service"), Value::from(service));
+    grpc.insert(Value::from("method"), Value::from(method));
+    Some(Value::from(grpc))
+}
+
 async fn parse_query(parts: Parts) -> Result<Value> {
     let map: BTreeMap<Value, Value> = parts.uri
         .query()
@@ -150,7 +244,7 @@ async fn parse_query(parts: Parts) -> Result<Value> {
                 .map(|(k, v)| (Value::from(k), Value::from(v)))
                 .collect()
         })
-        .unwrap_or_else(BTreeMap::new);
+        .unwrap_or_default();
 
     let v = Value::from(map);
     Ok(v)
@@ -161,11 +255,4 @@ fn handle_parse_error<E: std::fmt::Display>(err: E) -> RequestOrResponse {
     let mut res = Response::new(Body::from(error_message));
     *res.status_mut() = StatusCode::BAD_REQUEST;
     RequestOrResponse::Response(res)
-}
-
-fn handle_api_error<E: std::fmt::Display>(err: E) -> RequestOrResponse {
-    let error_message = err.to_string();
-    let mut res = Response::new(Body::from(error_message));
-    *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
-    RequestOrResponse::Response(res)
 }
\ No newline at end of file
@@ -0,0 +1,132 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use crossbeam_skiplist::SkipMap;
+use hudsucker::{WebSocketContext, WebSocketHandler};
+use hudsucker::hyper::{header, HeaderMap};
+use hudsucker::tokio_tungstenite::tungstenite::Message;
+use lazy_static::lazy_static;
+use log::info;
+use crate::admin::decisions;
+
+/// Longest part of a text message that is logged.
+const MAX_LOGGED: usize = 256;
+/// Injected values of connections that never sent a close frame are dropped after this long.
+const MAX_AGE: Duration = Duration::from_secs(24 * 3600);
+const REDACTED: &str = "[REDACTED]";
+
+lazy_static! {
+    /// Credentials injected into WebSocket handshakes, by client address, so they can be redacted from messages.
+    static ref INJECTED: SkipMap<SocketAddr, (Instant, Vec<String>)> = SkipMap::new();
+}
+
+pub fn is_upgrade(headers: &HeaderMap) -> bool {
+    headers.get(header::UPGRADE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.eq_ignore_ascii_case("websocket"))
+        .unwrap_or(false)
+}
+
+/// Remembers the values injected into the handshake of the client's WebSocket.
+pub fn remember(client: SocketAddr, values: Vec<String>) {
+    for entry in INJECTED.iter().filter(|entry| entry.value().0.elapsed() > MAX_AGE) {
+        entry.remove();
+    }
+
+    let mut injected = INJECTED.get(&client)
+        .map(|entry| entry.value().1.clone())
+        .unwrap_or_default();
+    injected.extend(values.into_iter().filter(|value| !value.is_empty()));
+    INJECTED.insert(client, (Instant::now(), injected));
+}
+
+fn forget(client: &SocketAddr) {
+    INJECTED.remove(client);
+}
+
+fn redact(text: &str, client: &SocketAddr) -> String {
+    let mut text = text.to_string();
+    if let Some(entry) = INJECTED.get(client) {
+        for value in &entry.value().1 {
+            text = text.replace(value.as_str(), REDACTED);
+        }
+    }
+    text
+}
+
+/// Redacts the whole text before truncating it, so a credential crossing the cut is never partly logged.
+fn logged_text(text: &str, client: &SocketAddr) -> String {
+    redact(text, client).chars().take(MAX_LOGGED).collect()
+}
+
+/// Logs WebSocket messages when enabled, with injected credentials redacted.
+///
+/// Clones share the switch, so `set_enabled` applies to every connection.
+#[derive(Clone, Default)]
+pub struct WebSocketLogHandler {
+    enabled: Arc<AtomicBool>,
+}
+
+impl WebSocketLogHandler {
+    pub fn new(enabled: bool) -> Self {
+        WebSocketLogHandler {
+            enabled: Arc::new(AtomicBool::new(enabled)),
+        }
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+}
+
+impl WebSocketHandler for WebSocketLogHandler {
+    async fn handle_message(&mut self, ctx: &WebSocketContext, message: Message) -> Option<Message> {
+        let (client, uri, direction) = match ctx {
+            WebSocketContext::ClientToServer { src, dst, .. } => (src, dst, "->"),
+            WebSocketContext::ServerToClient { src, dst, .. } => (dst, src, "<-"),
+        };
+
+        if self.enabled.load(Ordering::Relaxed) {
+            let content = match &message {
+                Message::Text(text) => logged_text(text, client),
+                Message::Binary(data) => format!("{} bytes of binary data", data.len()),
+                other => format!("{:?}", other),
+            };
+            info!("websocket {} {} {}: {}", client, direction, decisions::redact(uri), content);
+        }
+
+        if let Message::Close(_) = &message {
+            forget(client);
+        }
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redacts_credential_crossing_the_cut() {
+        let client: SocketAddr = "10.0.0.1:40000".parse().unwrap();
+        let secret = "s3cr3t-t0k3n";
+        remember(client, vec![secret.to_string()]);
+
+        let text = format!("{}{}", "a".repeat(MAX_LOGGED - 4), secret);
+        let logged = logged_text(&text, &client);
+        assert_eq!(logged.chars().count(), MAX_LOGGED);
+        assert!(!logged.contains("s3cr"));
+        assert!(logged.ends_with("[RED"));
+        forget(&client);
+    }
+
+    #[test]
+    fn redacts_before_the_cut() {
+        let client: SocketAddr = "10.0.0.2:40000".parse().unwrap();
+        remember(client, vec!["hunter2".to_string()]);
+
+        assert_eq!(logged_text("password=hunter2", &client), format!("password={}", REDACTED));
+        forget(&client);
+    }
+}
@@ -0,0 +1,192 @@
+use anyhow::{anyhow, Result};
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use x509_parser::extensions::GeneralName;
+use crate::identity::Identity;
+
+/// SAN format of SPIFFE IDs issued by Istio, Linkerd, SPIRE and cert-manager's csi-driver-spiffe
+/// for workloads of `trust_domain`.
+pub fn spiffe_format(trust_domain: &str) -> Result<String> {
+    if trust_domain.is_empty() || trust_domain.contains(['*', '{', '}', '/']) {
+        return Err(anyhow!("invalid SPIFFE trust domain {:?}", trust_domain));
+    }
+    Ok(format!("spiffe://{}/ns/{{namespace}}/sa/{{serviceAccount}}", trust_domain))
+}
+
+const NAMESPACE: &str = "namespace";
+const SERVICE_ACCOUNT: &str = "serviceAccount";
+const POD: &str = "pod";
+
+/// Maps the URI and DNS SANs of client certificates to identities.
+///
+/// In a format, `{namespace}`, `{serviceAccount}` and `{pod}` capture a part of the SAN and `*` matches
+/// anything, none of them across a `/`.
+#[derive(Clone, Debug)]
+pub struct IdentityFormats {
+    formats: Vec<String>,
+}
+
+impl IdentityFormats {
+    pub fn new(formats: Vec<String>) -> Result<Self> {
+        for format in &formats {
+            let fields: Vec<&str> = tokens(format).into_iter()
+                .filter_map(|token| match token {
+                    Token::Field(field) => Some(field),
+                    _ => None,
+                })
+                .collect();
+            if let Some(field) = fields.iter().find(|field| ![NAMESPACE, SERVICE_ACCOUNT, POD].contains(field)) {
+                return Err(anyhow!("unknown field {{{}}} in identity format {}", field, format));
+            }
+            if !fields.contains(&NAMESPACE) || !fields.contains(&SERVICE_ACCOUNT) {
+                return Err(anyhow!("identity format {} must contain {{namespace}} and {{serviceAccount}}", format));
+            }
+        }
+        Ok(IdentityFormats { formats })
+    }
+
+    /// The identity of the first SAN of the certificate matching a format, in the order of the formats.
+    pub fn identity(&self, cert: &CertificateDer<'_>) -> Result<Option<Identity>> {
+        let (_, parsed) = x509_parser::parse_x509_certificate(cert.as_ref())
+            .map_err(|e| anyhow!("failed to parse client certificate: {}", e))?;
+        let names: Vec<&str> = match parsed.subject_alternative_name()? {
+            Some(san) => san.value.general_names.iter()
+                .filter_map(|name| match name {
+                    GeneralName::URI(name) | GeneralName::DNSName(name) => Some(*name),
+                    _ => None,
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+
+        for format in &self.formats {
+            let tokens = tokens(format);
+            for name in &names {
+                let mut fields = Vec::new();
+                if !capture(&tokens, name, &mut fields) {
+                    continue;
+                }
+
+                let field = |key: &str| fields.iter().find(|(field, _)| *field == key).map(|(_, value)| value.to_string());
+                return Ok(Some(Identity {
+                    source: "certificate",
+                    namespace: field(NAMESPACE).unwrap_or_default(),
+                    service_account: field(SERVICE_ACCOUNT).unwrap_or_default(),
+                    pod: field(POD),
+                }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+enum Token<'a> {
+    Literal(&'a str),
+    Wildcard,
+    Field(&'a str),
+}
+
+fn tokens(format: &str) -> Vec<Token<'_>> {
+    let mut tokens = Vec::new();
+    let mut rest = format;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('*') {
+            tokens.push(Token::Wildcard);
+            rest = after;
+        } else if let Some((field, after)) = rest.strip_prefix('{').and_then(|rest| rest.split_once('}')) {
+            tokens.push(Token::Field(field));
+            rest = after;
+        } else {
+            let end = rest.char_indices().skip(1)
+                .find(|(_, c)| *c == '*' || *c == '{')
+                .map(|(i, _)| i)
+                .unwrap_or(rest.len());
+            tokens.push(Token::Literal(&rest[..end]));
+            rest = &rest[end..];
+        }
+    }
+    tokens
+}
+
+/// Matches `name` against the tokens, collecting the values of the fields.
+fn capture<'a>(tokens: &[Token<'_>], name: &'a str, fields: &mut Vec<(String, &'a str)>) -> bool {
+    match tokens.split_first() {
+        None => name.is_empty(),
+        Some((Token::Literal(literal), rest)) => name.strip_prefix(literal)
+            .is_some_and(|name| capture(rest, name, fields)),
+        Some((token, rest)) => {
+            let segment = name.find('/').unwrap_or(name.len());
+            for end in (1..=segment).filter(|end| name.is_char_boundary(*end)) {
+                if capture(rest, &name[end..], fields) {
+                    if let Token::Field(field) = token {
+                        fields.push((field.to_string(), &name[..end]));
+                    }
+                    return true;
+                }
+            }
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(format: &str, name: &str) -> Option<Vec<(String, String)>> {
+        let mut fields = Vec::new();
+        if !capture(&tokens(format), name, &mut fields) {
+            return None;
+        }
+        let mut fields: Vec<(String, String)> = fields.into_iter().map(|(field, value)| (field, value.to_string())).collect();
+        fields.sort();
+        Some(fields)
+    }
+
+    fn expected(values: &[(&str, &str)]) -> Option<Vec<(String, String)>> {
+        let mut fields: Vec<(String, String)> = values.iter().map(|(field, value)| (field.to_string(), value.to_string())).collect();
+        fields.sort();
+        Some(fields)
+    }
+
+    #[test]
+    fn captures_fields() {
+        let spiffe = spiffe_format("cluster.local").unwrap();
+        let cases = [
+            (spiffe.as_str(), "spiffe://cluster.local/ns/team-a/sa/builder", expected(&[("namespace", "team-a"), ("serviceAccount", "builder")])),
+            (spiffe.as_str(), "spiffe://evil.example/ns/team-a/sa/builder", None),
+            (spiffe.as_str(), "spiffe://cluster.local/ns/team-a/sa/builder/extra", None),
+            (spiffe.as_str(), "spiffe://cluster.local/ns/a/b/sa/builder", None),
+            (spiffe.as_str(), "spiffe://cluster.local/ns//sa/builder", None),
+            ("spiffe://*/ns/{namespace}/sa/{serviceAccount}", "spiffe://any.domain/ns/x/sa/y", expected(&[("namespace", "x"), ("serviceAccount", "y")])),
+            ("spiffe://*/ns/{namespace}/sa/{serviceAccount}", "spiffe://a/b/ns/x/sa/y", None),
+            ("{pod}.{serviceAccount}.{namespace}.svc", "web-0.web.shop.svc", expected(&[("namespace", "shop"), ("pod", "web-0"), ("serviceAccount", "web")])),
+            ("{pod}.{serviceAccount}.{namespace}.svc", "web-0.web.svc", None),
+            ("{serviceAccount}@{namespace}", "deployer@ci", expected(&[("namespace", "ci"), ("serviceAccount", "deployer")])),
+        ];
+        for (format, name, expected) in cases {
+            assert_eq!(fields(format, name), expected, "{} against {}", format, name);
+        }
+    }
+
+    #[test]
+    fn validates_formats() {
+        let cases = [
+            ("spiffe://cluster.local/ns/{namespace}/sa/{serviceAccount}", true),
+            ("{pod}.{serviceAccount}.{namespace}.svc", true),
+            ("spiffe://cluster.local/ns/{namespace}", false),
+            ("spiffe://cluster.local/sa/{serviceAccount}", false),
+            ("{namespace}/{serviceAccount}/{node}", false),
+        ];
+        for (format, valid) in cases {
+            assert_eq!(IdentityFormats::new(vec![format.to_string()]).is_ok(), valid, "{}", format);
+        }
+    }
+
+    #[test]
+    fn requires_a_plain_trust_domain() {
+        assert!(spiffe_format("cluster.local").is_ok());
+        for trust_domain in ["", "*", "{namespace}", "a/b"] {
+            assert!(spiffe_format(trust_domain).is_err(), "{:?}", trust_domain);
+        }
+    }
+}
@@ -0,0 +1,141 @@
+pub mod certificate;
+pub mod token;
+
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+use hudsucker::hyper::{header, Request, Response, StatusCode};
+use hudsucker::{Body, HttpContext};
+use hudsucker::tokio_tungstenite::tungstenite::http::Method;
+use lazy_static::lazy_static;
+use log::warn;
+use moka::future::Cache;
+use regorus::Value;
+use serde::{Deserialize, Serialize};
+use crate::apis::pod_meta::{self, PodMeta};
+use crate::relay;
+
+/// Identities of CONNECT tunnels are forgotten after this long without a new CONNECT from the same address.
+const CONNECTION_TTL: Duration = Duration::from_secs(3600);
+const MAX_CONNECTIONS: u64 = 100_000;
+
+lazy_static! {
+    /// Identities authenticated on a CONNECT, by client address, for the requests intercepted in the tunnel.
+    static ref CONNECTIONS: Cache<SocketAddr, Identity> = Cache::builder()
+        .max_capacity(MAX_CONNECTIONS)
+        .time_to_live(CONNECTION_TTL)
+        .build();
+}
+
+/// Whether clients have to authenticate to the proxy.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum ClientAuthMode {
+    /// clients are identified by their source IP only
+    #[default]
+    Disabled,
+    /// authenticated clients are identified by their credentials, the others by source IP
+    Optional,
+    /// requests without valid credentials are rejected
+    Required,
+}
+
+/// An authenticated workload identity.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Identity {
+    /// how the identity was established, `token` or `certificate`
+    pub source: &'static str,
+    pub namespace: String,
+    pub service_account: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pod: Option<String>,
+}
+
+impl Identity {
+    pub fn as_input(&self) -> Value {
+        let mut input: BTreeMap<Value, Value> = BTreeMap::new();
+        input.insert(Value::from("source"), Value::from(self.source));
+        input.insert(Value::from("namespace"), Value::from(self.namespace.as_str()));
+        input.insert(Value::from("serviceAccount"), Value::from(self.service_account.as_str()));
+        if let Some(pod) = &self.pod {
+            input.insert(Value::from("pod"), Value::from(pod.as_str()));
+        }
+        Value::from(input)
+    }
+
+    /// The cached metadata of the identity's pod, or of the pod at `ip` when it runs as the identity's
+    /// service account, or what the identity itself tells about the workload.
+    pub async fn meta(&self, ip: IpAddr) -> PodMeta {
+        let meta = match &self.pod {
+            Some(pod) => pod_meta::find_by_name(&self.namespace, pod),
+            None => pod_meta::resolve(ip).await,
+        };
+        if let Some(meta) = meta {
+            if meta.namespace == self.namespace && meta.service_account == self.service_account {
+                return meta.as_ref().clone();
+            }
+        }
+
+        PodMeta {
+            uid: String::new(),
+            name: self.pod.clone().unwrap_or_default(),
+            namespace: self.namespace.clone(),
+            service_account: self.service_account.clone(),
+            node_name: String::new(),
+            owner: None,
+            workload: None,
+            images: Vec::new(),
+            labels: BTreeMap::new(),
+            annotations: BTreeMap::new(),
+        }
+    }
+}
+
+/// Authenticates the request and removes its `Proxy-Authorization` header, so it never reaches upstreams.
+///
+/// The identity comes from the relayed connection, the request's own token or the token of the CONNECT
+/// that opened the tunnel, in that order. Returns the response to send instead when the request is rejected.
+pub async fn authenticate(ctx: &HttpContext, req: &mut Request<Body>) -> Result<Option<Identity>, Response<Body>> {
+    let authorization = req.headers_mut().remove(header::PROXY_AUTHORIZATION);
+    let mode = token::mode();
+    if mode == ClientAuthMode::Disabled {
+        return Ok(relay::origin(&ctx.client_addr).and_then(|origin| origin.identity));
+    }
+
+    let bearer = authorization.as_ref()
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim);
+    let identity = match (relay::origin(&ctx.client_addr).and_then(|origin| origin.identity), bearer) {
+        (Some(identity), _) => Some(identity),
+        (None, Some(token)) => match token::review(token).await {
+            Ok(identity) => identity,
+            Err(e) => {
+                warn!("failed to review the token of {}: {}", ctx.client_addr, e);
+                None
+            }
+        },
+        (None, None) if req.method() != Method::CONNECT => CONNECTIONS.get(&ctx.client_addr).await,
+        (None, None) => None,
+    };
+
+    if req.method() == Method::CONNECT {
+        match &identity {
+            Some(identity) => CONNECTIONS.insert(ctx.client_addr, identity.clone()).await,
+            None => CONNECTIONS.invalidate(&ctx.client_addr).await,
+        }
+    }
+
+    if identity.is_none() && mode == ClientAuthMode::Required {
+        return Err(proxy_authentication_required());
+    }
+    Ok(identity)
+}
+
+fn proxy_authentication_required() -> Response<Body> {
+    let mut res = Response::new(Body::from("a valid ServiceAccount token is required"));
+    *res.status_mut() = StatusCode::PROXY_AUTHENTICATION_REQUIRED;
+    res.headers_mut().insert(header::PROXY_AUTHENTICATE, header::HeaderValue::from_static("Bearer realm=\"auth-bridge\""));
+    res
+}
@@ -0,0 +1,101 @@
+use std::sync::RwLock;
+use std::time::Duration;
+use anyhow::Result;
+use k8s_openapi::api::authentication::v1::{TokenReview, TokenReviewSpec};
+use kube::{Api, Client};
+use kube::api::PostParams;
+use lazy_static::lazy_static;
+use log::debug;
+use moka::future::Cache;
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+use crate::identity::{ClientAuthMode, Identity};
+use crate::metrics;
+
+const SERVICE_ACCOUNT_PREFIX: &str = "system:serviceaccount:";
+const POD_NAME_EXTRA: &str = "authentication.kubernetes.io/pod-name";
+const MAX_CACHED: u64 = 10_000;
+
+/// How clients authenticate with projected ServiceAccount tokens.
+#[derive(Clone, Debug)]
+pub struct TokenAuth {
+    pub mode: ClientAuthMode,
+    /// audience tokens have to be issued for
+    pub audience: String,
+}
+
+lazy_static! {
+    static ref SETTINGS: RwLock<TokenAuth> = RwLock::new(TokenAuth {
+        mode: ClientAuthMode::Disabled,
+        audience: String::new(),
+    });
+    /// Review results by token hash, negative results included.
+    static ref REVIEWS: RwLock<Cache<String, Option<Identity>>> = RwLock::new(Cache::new(0));
+    /// Client the TokenReviews are created with, built on the first review.
+    static ref CLIENT: OnceCell<Client> = OnceCell::new();
+}
+
+pub fn configure(settings: TokenAuth, cache_ttl: Duration) {
+    *SETTINGS.write().unwrap() = settings;
+    *REVIEWS.write().unwrap() = Cache::builder()
+        .max_capacity(MAX_CACHED)
+        .time_to_live(cache_ttl)
+        .build();
+}
+
+pub fn mode() -> ClientAuthMode {
+    SETTINGS.read().unwrap().mode
+}
+
+/// Validates the token with a TokenReview, returns the identity of valid ServiceAccount tokens.
+pub async fn review(token: &str) -> Result<Option<Identity>> {
+    let key = format!("{:x}", Sha256::digest(token));
+    let cache = REVIEWS.read().unwrap().clone();
+    if let Some(identity) = cache.get(&key).await {
+        metrics::TOKEN_REVIEWS.with_label_values(&["cached"]).inc();
+        return Ok(identity);
+    }
+
+    let audience = SETTINGS.read().unwrap().audience.clone();
+    let review = TokenReview {
+        spec: TokenReviewSpec {
+            token: Some(token.to_string()),
+            audiences: Some(vec![audience.clone()]),
+        },
+        ..Default::default()
+    };
+
+    let client = CLIENT.get_or_try_init(Client::try_default).await?.clone();
+    let result = Api::<TokenReview>::all(client).create(&PostParams::default(), &review).await;
+    let status = match result {
+        Ok(review) => review.status.unwrap_or_default(),
+        Err(e) => {
+            metrics::TOKEN_REVIEWS.with_label_values(&["error"]).inc();
+            return Err(e.into());
+        }
+    };
+
+    let audience_matches = status.audiences.as_ref().map(|audiences| audiences.contains(&audience)).unwrap_or(false);
+    let identity = match (status.authenticated, status.user) {
+        (Some(true), Some(user)) if audience_matches => {
+            let username = user.username.unwrap_or_default();
+            username.strip_prefix(SERVICE_ACCOUNT_PREFIX)
+                .and_then(|name| name.split_once(':'))
+                .map(|(namespace, service_account)| Identity {
+                    source: "token",
+                    namespace: namespace.to_string(),
+                    service_account: service_account.to_string(),
+                    pod: user.extra.as_ref()
+                        .and_then(|extra| extra.get(POD_NAME_EXTRA))
+                        .and_then(|values| values.first().cloned()),
+                })
+        }
+        _ => None,
+    };
+
+    debug!("token review result: {:?}", identity);
+    let label = if identity.is_some() { "authenticated" } else { "rejected" };
+    metrics::TOKEN_REVIEWS.with_label_values(&[label]).inc();
+    cache.insert(key, identity.clone()).await;
+    Ok(identity)
+}
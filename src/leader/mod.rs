@@ -0,0 +1,148 @@
+use std::time::Duration;
+use anyhow::Result;
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::MicroTime;
+use k8s_openapi::chrono::{self, Utc};
+use kube::{Api, Client};
+use kube::api::{ObjectMeta, PostParams};
+use log::{error, info, warn};
+use crate::metrics;
+
+/// How long a lease stays valid without being renewed.
+const LEASE_DURATION: Duration = Duration::from_secs(15);
+/// How often the holder renews the lease, and others try to take it over.
+const RENEW_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug)]
+pub struct LeaseConfig {
+    pub namespace: String,
+    pub name: String,
+    /// name of this replica, recorded as the lease holder
+    pub identity: String,
+}
+
+/// Waits until this replica holds the lease and keeps renewing it in the background.
+///
+/// Work started after this returns runs in a single replica at a time. A replica that fails to renew the lease
+/// before it expires exits, so it never keeps working next to the replica that took the lease over.
+pub async fn acquire(client: Client, config: LeaseConfig) -> Result<()> {
+    let api = Api::<Lease>::namespaced(client, &config.namespace);
+    let mut interval = tokio::time::interval(RENEW_INTERVAL);
+
+    info!("waiting for lease {}/{}", config.namespace, config.name);
+    loop {
+        interval.tick().await;
+        match try_acquire(&api, &config).await {
+            Ok(true) => break,
+            Ok(false) => {}
+            Err(e) => warn!("failed to acquire lease {}/{}: {}", config.namespace, config.name, e),
+        }
+    }
+    info!("acquired lease {}/{} as {}", config.namespace, config.name, config.identity);
+    metrics::LEADER.set(1);
+
+    tokio::spawn(async move {
+        let mut renewed = tokio::time::Instant::now();
+        loop {
+            interval.tick().await;
+            match try_acquire(&api, &config).await {
+                Ok(true) => renewed = tokio::time::Instant::now(),
+                Ok(false) => {
+                    error!("lost lease {}/{}", config.namespace, config.name);
+                    std::process::exit(1);
+                }
+                Err(e) if renewed.elapsed() + RENEW_INTERVAL >= LEASE_DURATION => {
+                    error!("failed to renew lease {}/{} before it expires: {}", config.namespace, config.name, e);
+                    std::process::exit(1);
+                }
+                Err(e) => warn!("failed to renew lease {}/{}: {}", config.namespace, config.name, e),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Takes or renews the lease, returns whether this replica holds it now.
+async fn try_acquire(api: &Api<Lease>, config: &LeaseConfig) -> Result<bool> {
+    let now = Utc::now();
+
+    let Some(mut lease) = api.get_opt(&config.name).await? else {
+        let lease = Lease {
+            metadata: ObjectMeta {
+                name: Some(config.name.clone()),
+                namespace: Some(config.namespace.clone()),
+                ..ObjectMeta::default()
+            },
+            spec: Some(LeaseSpec {
+                holder_identity: Some(config.identity.clone()),
+                lease_duration_seconds: Some(LEASE_DURATION.as_secs() as i32),
+                acquire_time: Some(MicroTime(now)),
+                renew_time: Some(MicroTime(now)),
+                lease_transitions: Some(0),
+            }),
+        };
+        return match api.create(&PostParams::default(), &lease).await {
+            Ok(_) => Ok(true),
+            Err(kube::Error::Api(err)) if err.code == 409 => Ok(false),
+            Err(e) => Err(e.into()),
+        };
+    };
+
+    let spec = lease.spec.get_or_insert_with(LeaseSpec::default);
+    let held = spec.holder_identity.as_deref() == Some(config.identity.as_str());
+    if !held && !is_expired(spec, now) {
+        return Ok(false);
+    }
+
+    if !held {
+        info!("taking over lease {}/{} from {}", config.namespace, config.name, spec.holder_identity.clone().unwrap_or_default());
+        spec.holder_identity = Some(config.identity.clone());
+        spec.acquire_time = Some(MicroTime(now));
+        spec.lease_transitions = Some(spec.lease_transitions.unwrap_or_default() + 1);
+    }
+    spec.lease_duration_seconds = Some(LEASE_DURATION.as_secs() as i32);
+    spec.renew_time = Some(MicroTime(now));
+
+    // the resource version is kept, so only one of several replicas taking over at once succeeds
+    match api.replace(&config.name, &PostParams::default(), &lease).await {
+        Ok(_) => Ok(true),
+        Err(kube::Error::Api(err)) if err.code == 409 => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn is_expired(spec: &LeaseSpec, now: chrono::DateTime<Utc>) -> bool {
+    let duration = chrono::Duration::seconds(spec.lease_duration_seconds.unwrap_or_default().into());
+    match (&spec.holder_identity, &spec.renew_time) {
+        (Some(holder), Some(renewed)) if !holder.is_empty() => renewed.0 + duration < now,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_after_the_lease_duration() {
+        let now = Utc::now();
+        let spec = |holder: Option<&str>, renewed_ago: i64| LeaseSpec {
+            holder_identity: holder.map(String::from),
+            lease_duration_seconds: Some(15),
+            renew_time: Some(MicroTime(now - chrono::Duration::seconds(renewed_ago))),
+            ..LeaseSpec::default()
+        };
+
+        let cases = [
+            (spec(Some("a"), 5), false),
+            (spec(Some("a"), 16), true),
+            (spec(None, 5), true),
+            (spec(Some(""), 5), true),
+            (LeaseSpec::default(), true),
+        ];
+        for (spec, expired) in cases {
+            assert_eq!(is_expired(&spec, now), expired, "{:?}", spec);
+        }
+    }
+}
@@ -1,5 +1,17 @@
+pub mod admin;
 pub mod apis;
+pub mod ca;
+pub mod config;
 pub mod handlers;
+pub mod identity;
+pub mod leader;
+pub mod metrics;
+pub mod relay;
 pub mod cmd;
 pub mod secret;
-
+pub mod server;
+pub mod shutdown;
+pub mod stats;
+pub mod telemetry;
+pub mod upstream;
+pub mod webhook;
@@ -1,11 +1,16 @@
 use clap::{Parser, Subcommand};
-use auth_bridge::cmd::{proxy,controller};
+use auth_bridge::cmd::{ca, crd, proxy, controller};
+use auth_bridge::telemetry;
 use anyhow::Result;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 #[command(propagate_version = true)]
 struct Cli {
+    /// OTLP gRPC endpoint traces are exported to, e.g. http://localhost:4317
+    #[arg(long, global = true, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    otlp_endpoint: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -13,24 +18,44 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Adds files to myapp
-    Controller,
-    Proxy(proxy::Args)
+    Controller(Box<controller::Args>),
+    Proxy(Box<proxy::Args>),
+    /// Print or install the custom resource definitions
+    #[command(subcommand)]
+    Crd(crd::Commands),
+    /// Manage the proxy CA
+    #[command(subcommand)]
+    Ca(ca::Commands),
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-
     let cli = Cli::parse();
 
+    let service_name = match &cli.command {
+        Commands::Proxy(_) => "auth-bridge-proxy",
+        Commands::Controller(_) => "auth-bridge-controller",
+        _ => "auth-bridge",
+    };
+    telemetry::init(cli.otlp_endpoint.as_deref(), service_name)?;
+
     // You can check for the existence of subcommands, and if found use their
     // matches just as you would the top level cmd
-    match &cli.command {
+    let result = match &cli.command {
         Commands::Proxy(args) => {
             proxy::run(args).await
         },
-        Commands::Controller => {
-            controller::run().await
+        Commands::Controller(args) => {
+            controller::run(args).await
+        }
+        Commands::Crd(command) => {
+            crd::run(command).await
         }
-    }
+        Commands::Ca(command) => {
+            ca::run(command)
+        }
+    };
+
+    telemetry::shutdown();
+    result
 }
\ No newline at end of file
@@ -0,0 +1,134 @@
+use std::net::SocketAddr;
+use anyhow::Result;
+use hyper::{body::Incoming, Request, StatusCode};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+use crate::server::{self, response};
+
+lazy_static! {
+    pub static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "auth_bridge_requests_total",
+        "Requests handled by the policy handler, by policy, matched host pattern and outcome",
+        &["policy", "host", "outcome"]
+    ).unwrap();
+    pub static ref POLICY_EVALUATION: HistogramVec = register_histogram_vec!(
+        "auth_bridge_policy_evaluation_duration_seconds",
+        "Time spent evaluating the rules of a policy",
+        &["policy"]
+    ).unwrap();
+    pub static ref SECRET_FETCH: HistogramVec = register_histogram_vec!(
+        "auth_bridge_secret_fetch_duration_seconds",
+        "Time spent fetching the credentials of a policy",
+        &["provider"]
+    ).unwrap();
+    pub static ref SECRET_FETCH_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "auth_bridge_secret_fetch_errors_total",
+        "Failed credential fetches",
+        &["provider"]
+    ).unwrap();
+    pub static ref POD_CACHE_SIZE: IntGauge = register_int_gauge!(
+        "auth_bridge_pod_cache_size",
+        "Pod IPs in the pod cache"
+    ).unwrap();
+    pub static ref UNKNOWN_CLIENTS: IntCounter = register_int_counter!(
+        "auth_bridge_unknown_client_total",
+        "Requests from client IPs that are not in the pod cache"
+    ).unwrap();
+    pub static ref POD_LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "auth_bridge_pod_lookups_total",
+        "Cluster-wide lookups of client IPs missing from the pod cache, by result",
+        &["result"]
+    ).unwrap();
+    pub static ref WORKLOAD_LOOKUPS: IntCounterVec = register_int_counter_vec!(
+        "auth_bridge_workload_lookups_total",
+        "Lookups of the workload behind a ReplicaSet or Job, by result",
+        &["result"]
+    ).unwrap();
+    pub static ref TOKEN_REVIEWS: IntCounterVec = register_int_counter_vec!(
+        "auth_bridge_token_reviews_total",
+        "ServiceAccount token validations, by result",
+        &["result"]
+    ).unwrap();
+    pub static ref CERT_CACHE: IntCounterVec = register_int_counter_vec!(
+        "auth_bridge_cert_cache_total",
+        "Lookups of forged MITM certificates, by result",
+        &["result"]
+    ).unwrap();
+    pub static ref CONNECTS: IntCounterVec = register_int_counter_vec!(
+        "auth_bridge_connect_total",
+        "CONNECT requests, by whether they were intercepted or tunneled",
+        &["decision"]
+    ).unwrap();
+    pub static ref CLIENT_CERTIFICATES: IntCounterVec = register_int_counter_vec!(
+        "auth_bridge_client_certificates_total",
+        "Client certificates presented to a mutual TLS listener, by result",
+        &["result"]
+    ).unwrap();
+    pub static ref RELAYED_CONNECTIONS: IntCounterVec = register_int_counter_vec!(
+        "auth_bridge_relayed_connections_total",
+        "Connections accepted by a SOCKS5, transparent or TLS listener and relayed into the proxy, by listener",
+        &["listener"]
+    ).unwrap();
+    pub static ref UPSTREAM_RESPONSES: IntCounterVec = register_int_counter_vec!(
+        "auth_bridge_upstream_responses_total",
+        "Responses received from upstream servers, by status code",
+        &["status"]
+    ).unwrap();
+    pub static ref UPSTREAM_TLS_INSECURE: IntCounterVec = register_int_counter_vec!(
+        "auth_bridge_upstream_tls_insecure_total",
+        "Upstream TLS handshakes whose certificate was not verified, by configured host pattern",
+        &["host"]
+    ).unwrap();
+    pub static ref WATCHER_RESTARTS: IntCounterVec = register_int_counter_vec!(
+        "auth_bridge_watcher_restarts_total",
+        "Full relists of Kubernetes watches, by resource",
+        &["resource"]
+    ).unwrap();
+    pub static ref LEADER: IntGauge = register_int_gauge!(
+        "auth_bridge_leader",
+        "1 while this controller replica holds the lease of the cluster-wide reconcilers"
+    ).unwrap();
+    pub static ref RECONCILES: IntCounterVec = register_int_counter_vec!(
+        "auth_bridge_reconcile_total",
+        "Controller reconcile runs, by reconciler",
+        &["reconciler"]
+    ).unwrap();
+    pub static ref RECONCILE_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "auth_bridge_reconcile_errors_total",
+        "Failed controller reconcile runs, by reconciler",
+        &["reconciler"]
+    ).unwrap();
+}
+
+/// Counts a reconcile run and, when it failed, the failure.
+pub fn reconciled<T, E>(reconciler: &str, result: &std::result::Result<T, E>) {
+    RECONCILES.with_label_values(&[reconciler]).inc();
+    if result.is_err() {
+        RECONCILE_ERRORS.with_label_values(&[reconciler]).inc();
+    }
+}
+
+pub fn encode() -> server::Response {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        return response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string());
+    }
+
+    let mut res = response(StatusCode::OK, buffer);
+    res.headers_mut().insert(hyper::header::CONTENT_TYPE, encoder.format_type().parse().unwrap());
+    res
+}
+
+/// Serves `/metrics` on its own listener, for components without an admin listener.
+pub async fn serve(addr: SocketAddr) -> Result<()> {
+    server::serve(addr, None, |req: Request<Incoming>| async move {
+        match req.uri().path() {
+            "/metrics" => encode(),
+            _ => response(StatusCode::NOT_FOUND, "not found"),
+        }
+    }).await
+}
@@ -0,0 +1,114 @@
+pub mod sniff;
+pub mod socks5;
+pub mod tls;
+pub mod transparent;
+
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use anyhow::{anyhow, Result};
+use crossbeam_skiplist::SkipMap;
+use lazy_static::lazy_static;
+use log::debug;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use crate::identity::Identity;
+use crate::metrics;
+
+/// Upper bound of the response header of the relay listener to a CONNECT.
+const MAX_CONNECT_RESPONSE: usize = 8 * 1024;
+
+lazy_static! {
+    /// Where each relayed connection came from, by the local address of its connection to the relay listener.
+    static ref ORIGINS: SkipMap<SocketAddr, Origin> = SkipMap::new();
+}
+
+/// Loopback address of the proxy listener relayed connections are fed into.
+static LISTENER: OnceLock<SocketAddr> = OnceLock::new();
+
+/// The client behind a relayed connection.
+#[derive(Clone, Debug)]
+pub struct Origin {
+    pub peer: SocketAddr,
+    /// name of the listener the client connected to, e.g. `socks5`
+    pub listener: &'static str,
+    /// identity the listener authenticated the client with
+    pub identity: Option<Identity>,
+}
+
+pub fn set_listener(addr: SocketAddr) {
+    LISTENER.set(addr).expect("relay listener is already set");
+}
+
+/// The origin of a connection accepted by the proxy, if it was relayed.
+pub fn origin(client_addr: &SocketAddr) -> Option<Origin> {
+    ORIGINS.get(client_addr).map(|entry| entry.value().clone())
+}
+
+/// The address of the real client of a connection accepted by the proxy.
+pub fn client_addr(client_addr: SocketAddr) -> SocketAddr {
+    origin(&client_addr).map(|origin| origin.peer).unwrap_or(client_addr)
+}
+
+/// A connection to the relay listener on behalf of a client, registered until it is dropped.
+pub struct Relay {
+    stream: TcpStream,
+    local: SocketAddr,
+}
+
+impl Relay {
+    pub async fn open(origin: Origin) -> Result<Self> {
+        let listener = LISTENER.get().ok_or(anyhow!("relay listener is not running"))?;
+        let stream = TcpStream::connect(listener).await?;
+        stream.set_nodelay(true)?;
+        let local = stream.local_addr()?;
+
+        debug!("relaying {} connection from {} through {}", origin.listener, origin.peer, local);
+        metrics::RELAYED_CONNECTIONS.with_label_values(&[origin.listener]).inc();
+        ORIGINS.insert(local, origin);
+        Ok(Relay { stream, local })
+    }
+
+    /// Asks the proxy for a tunnel to `authority`, which it intercepts or passes through like any CONNECT.
+    pub async fn connect(&mut self, authority: &str) -> Result<()> {
+        let request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n\r\n");
+        self.stream.write_all(request.as_bytes()).await?;
+
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() >= MAX_CONNECT_RESPONSE {
+                return Err(anyhow!("response of the relay listener is too large"));
+            }
+            response.push(self.stream.read_u8().await?);
+        }
+
+        let status_line = String::from_utf8_lossy(&response);
+        match status_line.split_whitespace().nth(1) {
+            Some(status) if status.starts_with('2') => Ok(()),
+            status => Err(anyhow!("relay listener refused CONNECT to {}: {:?}", authority, status)),
+        }
+    }
+
+    /// Copies data between the client and the proxy until either side closes.
+    pub async fn splice<S>(mut self, client: &mut S) -> Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        tokio::io::copy_bidirectional(client, &mut self.stream).await?;
+        Ok(())
+    }
+}
+
+impl Drop for Relay {
+    fn drop(&mut self) {
+        ORIGINS.remove(&self.local);
+    }
+}
+
+/// Formats a host and port as a CONNECT authority, bracketing IPv6 addresses.
+pub fn authority(host: &str, port: u16) -> String {
+    if host.contains(':') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
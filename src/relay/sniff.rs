@@ -0,0 +1,158 @@
+//! Recovers the requested host name from the first bytes a client sends.
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const SERVER_NAME_EXTENSION: u16 = 0x0000;
+const HOST_NAME: u8 = 0x00;
+
+pub fn is_tls(data: &[u8]) -> bool {
+    data.len() >= 2 && data[0] == TLS_HANDSHAKE && data[1] == 0x03
+}
+
+/// Returns true once `data` holds the complete first TLS record, or the client hello can't be in it.
+pub fn tls_record_complete(data: &[u8]) -> bool {
+    if data.len() < 5 {
+        return false;
+    }
+    let length = u16::from_be_bytes([data[3], data[4]]) as usize;
+    data.len() >= 5 + length
+}
+
+/// The server name of a TLS client hello.
+pub fn server_name(data: &[u8]) -> Option<String> {
+    let mut reader = Reader(data);
+    if reader.u8()? != TLS_HANDSHAKE {
+        return None;
+    }
+    reader.skip(2)?; // record version
+    let length = reader.u16()? as usize;
+    let mut record = Reader(reader.bytes(length)?);
+
+    if record.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    record.skip(3)?; // handshake length
+    record.skip(2 + 32)?; // client version and random
+    let session_id = record.u8()? as usize;
+    record.skip(session_id)?;
+    let cipher_suites = record.u16()? as usize;
+    record.skip(cipher_suites)?;
+    let compression_methods = record.u8()? as usize;
+    record.skip(compression_methods)?;
+
+    let length = record.u16()? as usize;
+    let mut extensions = Reader(record.bytes(length)?);
+    while let Some(kind) = extensions.u16() {
+        let length = extensions.u16()? as usize;
+        let mut extension = Reader(extensions.bytes(length)?);
+        if kind != SERVER_NAME_EXTENSION {
+            continue;
+        }
+
+        let length = extension.u16()? as usize;
+        let mut names = Reader(extension.bytes(length)?);
+        while let Some(name_type) = names.u8() {
+            let length = names.u16()? as usize;
+            let name = names.bytes(length)?;
+            if name_type == HOST_NAME {
+                return String::from_utf8(name.to_vec()).ok();
+            }
+        }
+    }
+    None
+}
+
+/// The host, without port, of the `Host` header of a plain HTTP request.
+pub fn http_host(data: &[u8]) -> Option<String> {
+    let head = std::str::from_utf8(data).ok()?;
+    let header = head.lines()
+        .skip(1)
+        .take_while(|line| !line.is_empty())
+        .find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case("host").then(|| value.trim())
+        })?;
+
+    let host = match header.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next().unwrap_or(bracketed),
+        None => header.split(':').next().unwrap_or(header),
+    };
+    Some(host.to_string())
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, count: usize) -> Option<&'a [u8]> {
+        if self.0.len() < count {
+            return None;
+        }
+        let (bytes, rest) = self.0.split_at(count);
+        self.0 = rest;
+        Some(bytes)
+    }
+
+    fn skip(&mut self, count: usize) -> Option<()> {
+        self.bytes(count).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use tokio_rustls::rustls::{ClientConfig, ClientConnection, RootCertStore};
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use super::*;
+
+    /// The first flight rustls sends when connecting to `name`.
+    fn client_hello(name: &str) -> Vec<u8> {
+        let config = ClientConfig::builder()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let name = ServerName::try_from(name.to_string()).unwrap();
+        let mut connection = ClientConnection::new(Arc::new(config), name).unwrap();
+        let mut data = Vec::new();
+        connection.write_tls(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn reads_server_name_of_client_hello() {
+        let data = client_hello("api.example.com");
+        assert!(is_tls(&data));
+        assert!(tls_record_complete(&data));
+        assert_eq!(server_name(&data).as_deref(), Some("api.example.com"));
+    }
+
+    #[test]
+    fn truncated_record_has_no_server_name() {
+        let data = client_hello("api.example.com");
+        let truncated = &data[..data.len() - 10];
+        assert!(!tls_record_complete(truncated));
+        assert_eq!(server_name(truncated), None);
+        assert_eq!(server_name(&data[..3]), None);
+    }
+
+    #[test]
+    fn client_hello_without_sni_has_no_server_name() {
+        // clients never send SNI for IP addresses
+        let data = client_hello("10.0.0.1");
+        assert!(tls_record_complete(&data));
+        assert_eq!(server_name(&data), None);
+    }
+
+    #[test]
+    fn reads_host_header() {
+        let data = b"POST /upload HTTP/1.1\r\nhost: [::1]:8080\r\n\r\n";
+        assert_eq!(http_host(data).as_deref(), Some("::1"));
+        assert_eq!(http_host(b"GET / HTTP/1.1\r\nAccept: */*\r\n\r\n"), None);
+    }
+}
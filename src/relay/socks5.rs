@@ -0,0 +1,106 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use crate::relay::{self, Origin, Relay};
+use crate::shutdown;
+
+const VERSION: u8 = 0x05;
+const NO_AUTHENTICATION: u8 = 0x00;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CMD_CONNECT: u8 = 0x01;
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_GENERAL_FAILURE: u8 = 0x01;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+/// Accepts SOCKS5 CONNECTs and relays them into the proxy, until the proxy stops.
+pub async fn serve(addr: SocketAddr) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    info!("SOCKS5 listening on {}", addr);
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown::stopped() => return Ok(()),
+        };
+
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, peer).await {
+                debug!("SOCKS5 connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+async fn handle(mut stream: TcpStream, peer: SocketAddr) -> Result<()> {
+    stream.set_nodelay(true)?;
+
+    // greeting: VER NMETHODS METHODS
+    let version = stream.read_u8().await?;
+    if version != VERSION {
+        return Err(anyhow!("unsupported SOCKS version {}", version));
+    }
+    let count = stream.read_u8().await?;
+    let mut methods = vec![0; count as usize];
+    stream.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTHENTICATION) {
+        stream.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        return Err(anyhow!("client offers no supported authentication method"));
+    }
+    stream.write_all(&[VERSION, NO_AUTHENTICATION]).await?;
+
+    // request: VER CMD RSV ATYP DST.ADDR DST.PORT
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await?;
+    let [_, command, _, address_type] = header;
+    let host = match address_type {
+        ATYP_IPV4 => {
+            let mut octets = [0; 4];
+            stream.read_exact(&mut octets).await?;
+            Ipv4Addr::from(octets).to_string()
+        }
+        ATYP_IPV6 => {
+            let mut octets = [0; 16];
+            stream.read_exact(&mut octets).await?;
+            Ipv6Addr::from(octets).to_string()
+        }
+        ATYP_DOMAIN => {
+            let length = stream.read_u8().await?;
+            let mut name = vec![0; length as usize];
+            stream.read_exact(&mut name).await?;
+            String::from_utf8(name)?
+        }
+        other => {
+            reply(&mut stream, REPLY_ADDRESS_TYPE_NOT_SUPPORTED).await?;
+            return Err(anyhow!("unsupported address type {}", other));
+        }
+    };
+    let port = stream.read_u16().await?;
+
+    if command != CMD_CONNECT {
+        reply(&mut stream, REPLY_COMMAND_NOT_SUPPORTED).await?;
+        return Err(anyhow!("unsupported command {}", command));
+    }
+
+    let authority = relay::authority(&host, port);
+    let mut relay = Relay::open(Origin { peer, listener: "socks5", identity: None }).await?;
+    if let Err(e) = relay.connect(&authority).await {
+        warn!("failed to relay SOCKS5 connection from {} to {}: {}", peer, authority, e);
+        reply(&mut stream, REPLY_GENERAL_FAILURE).await?;
+        return Err(e);
+    }
+    reply(&mut stream, REPLY_SUCCEEDED).await?;
+
+    relay.splice(&mut stream).await
+}
+
+/// Replies with the given code and an unspecified bound address.
+async fn reply(stream: &mut TcpStream, code: u8) -> Result<()> {
+    stream.write_all(&[VERSION, code, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0]).await?;
+    Ok(())
+}
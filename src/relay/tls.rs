@@ -0,0 +1,197 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use k8s_openapi::api::core::v1::Secret;
+use kube::{Api, Client};
+use kube::runtime::{watcher, WatchStreamExt};
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use crate::ca::{CERT_KEY, KEY_KEY};
+use crate::identity::certificate::{self, IdentityFormats};
+use crate::metrics;
+use crate::relay::{Origin, Relay};
+use crate::server::tls::CertResolver;
+use crate::shutdown;
+use crate::upstream::tls::{load_certificates, TrustConfig};
+
+/// How long startup waits for the listener certificate before giving up.
+const CERTIFICATE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A listener terminating TLS in front of the proxy.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TlsListenerConfig {
+    /// addresses of the listeners
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub listen: Vec<SocketAddr>,
+    /// secret with the `tls.crt` and `tls.key` served to clients, as <namespace>/<name>, reloaded when it changes
+    pub certificate_secret: String,
+}
+
+/// A listener requiring client certificates, whose SANs identify the client.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MtlsListenerConfig {
+    #[serde(flatten)]
+    pub listener: TlsListenerConfig,
+    /// CAs client certificates have to be issued by
+    #[serde(default)]
+    pub client_ca: TrustConfig,
+    /// SAN formats identities are read from, the first matching one wins,
+    /// defaults to `spiffe://<trustDomain>/ns/{namespace}/sa/{serviceAccount}`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub identity_formats: Vec<String>,
+    /// trust domain of the SPIFFE IDs accepted by the default identity format, e.g. `cluster.local`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trust_domain: Option<String>,
+}
+
+impl MtlsListenerConfig {
+    pub fn identity_formats(&self) -> Result<IdentityFormats> {
+        if self.identity_formats.is_empty() {
+            let trust_domain = self.trust_domain.as_ref()
+                .ok_or(anyhow!("the mutual TLS listener needs a trust domain or identity formats"))?;
+            return IdentityFormats::new(vec![certificate::spiffe_format(trust_domain)?]);
+        }
+        IdentityFormats::new(self.identity_formats.clone())
+    }
+
+    /// A server config serving the certificate of `resolver` to clients with a certificate of the client CAs.
+    pub async fn server_config(&self, resolver: &Arc<CertResolver>) -> Result<Arc<ServerConfig>> {
+        let mut roots = RootCertStore::empty();
+        let (added, ignored) = roots.add_parsable_certificates(load_certificates(&self.client_ca).await?);
+        if ignored > 0 {
+            warn!("ignored {} invalid client CA certificates", ignored);
+        }
+        if added == 0 {
+            return Err(anyhow!("the mutual TLS listener has no client CA"));
+        }
+
+        let verifier = WebPkiClientVerifier::builder(Arc::new(roots)).build()?;
+        Ok(resolver.server_config_with_client_verifier(verifier))
+    }
+}
+
+/// Serves the `tls.crt` and `tls.key` of a Secret, following its rotations.
+///
+/// Resolves once the first certificate has been loaded, the watch keeps running in the background.
+pub async fn watch_certificate(secret: &str) -> Result<Arc<CertResolver>> {
+    let (namespace, name) = secret.split_once('/')
+        .ok_or(anyhow!("certificate secret {} must be in the form <namespace>/<name>", secret))?;
+    let client = Client::try_default().await?;
+    let api = Api::<Secret>::namespaced(client, namespace);
+    let config = watcher::Config::default().fields(&format!("metadata.name={}", name));
+    let secret = secret.to_string();
+    let missing = anyhow!("no valid certificate found in secret {} within {:?}", secret, CERTIFICATE_TIMEOUT);
+
+    let resolver = Arc::new(CertResolver::default());
+    let watched = resolver.clone();
+    let (tx, rx) = oneshot::channel();
+    let watch = tokio::spawn(async move {
+        let mut tx = Some(tx);
+        let mut stream = watcher(api, config).applied_objects().boxed();
+        while let Some(event) = stream.next().await {
+            let data = match event {
+                Ok(object) => object.data.unwrap_or_default(),
+                Err(e) => {
+                    error!("listener certificate watch error: {}", e);
+                    continue;
+                }
+            };
+
+            let (Some(cert), Some(key)) = (data.get(CERT_KEY), data.get(KEY_KEY)) else {
+                error!("secret {} does not contain {} and {}", secret, CERT_KEY, KEY_KEY);
+                continue;
+            };
+            match watched.set_pem(&cert.0, &key.0) {
+                Ok(()) => {
+                    info!("loaded listener certificate from secret {}", secret);
+                    if let Some(tx) = tx.take() {
+                        let _ = tx.send(());
+                    }
+                }
+                Err(e) => error!("invalid listener certificate in secret {}: {}", secret, e),
+            }
+        }
+    });
+
+    match tokio::time::timeout(CERTIFICATE_TIMEOUT, rx).await {
+        Ok(loaded) => loaded?,
+        Err(_) => {
+            watch.abort();
+            return Err(missing);
+        }
+    }
+    Ok(resolver)
+}
+
+/// Accepts TLS connections and relays the decrypted streams into the proxy, until the proxy stops.
+///
+/// With identity formats, clients are identified by the SANs of their certificate and rejected when none matches.
+pub async fn serve(
+    addr: SocketAddr,
+    config: Arc<ServerConfig>,
+    name: &'static str,
+    formats: Option<Arc<IdentityFormats>>,
+) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    let acceptor = TlsAcceptor::from(config);
+    info!("{} proxy listening on {}", name, addr);
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown::stopped() => return Ok(()),
+        };
+
+        let acceptor = acceptor.clone();
+        let formats = formats.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, peer, acceptor, name, formats).await {
+                debug!("{} connection from {} failed: {}", name, peer, e);
+            }
+        });
+    }
+}
+
+async fn handle(
+    stream: TcpStream,
+    peer: SocketAddr,
+    acceptor: TlsAcceptor,
+    name: &'static str,
+    formats: Option<Arc<IdentityFormats>>,
+) -> Result<()> {
+    stream.set_nodelay(true)?;
+    let mut stream = acceptor.accept(stream).await?;
+
+    let identity = match formats {
+        Some(formats) => {
+            let cert = stream.get_ref().1.peer_certificates()
+                .and_then(|certs| certs.first())
+                .ok_or(anyhow!("client presented no certificate"))?;
+            match formats.identity(cert)? {
+                Some(identity) => {
+                    metrics::CLIENT_CERTIFICATES.with_label_values(&["authenticated"]).inc();
+                    Some(identity)
+                }
+                None => {
+                    metrics::CLIENT_CERTIFICATES.with_label_values(&["unmapped"]).inc();
+                    warn!("certificate of {} matches no identity format", peer);
+                    return Ok(());
+                }
+            }
+        }
+        None => None,
+    };
+
+    // the client speaks the proxy protocol itself, its requests and CONNECTs go to the proxy as they are
+    let relay = Relay::open(Origin { peer, listener: name, identity }).await?;
+    relay.splice(&mut stream).await
+}
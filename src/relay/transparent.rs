@@ -0,0 +1,120 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, SockRef, Socket, Type};
+use tokio::net::{TcpListener, TcpStream};
+use crate::relay::{self, sniff, Origin, Relay};
+use crate::shutdown;
+
+/// Most bytes peeked at to find the requested host.
+const MAX_PEEK: usize = 16 * 1024;
+/// How long a client may take to send enough bytes to find the requested host.
+const PEEK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How redirected connections reach the transparent listener.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum TransparentMode {
+    /// iptables/nftables REDIRECT, the destination is read with SO_ORIGINAL_DST
+    #[default]
+    Redirect,
+    /// TPROXY, the destination is the local address of the accepted socket
+    Tproxy,
+}
+
+/// Accepts redirected connections and relays them into the proxy, until the proxy stops.
+pub async fn serve(addr: SocketAddr, mode: TransparentMode) -> Result<()> {
+    let listener = bind(addr, mode)?;
+    info!("transparent proxy listening on {} ({:?})", addr, mode);
+
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown::stopped() => return Ok(()),
+        };
+
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, peer, addr, mode).await {
+                debug!("transparent connection from {} failed: {}", peer, e);
+            }
+        });
+    }
+}
+
+fn bind(addr: SocketAddr, mode: TransparentMode) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if mode == TransparentMode::Tproxy {
+        socket.set_ip_transparent(true)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(socket.into())?)
+}
+
+fn original_destination(stream: &TcpStream, mode: TransparentMode) -> Result<SocketAddr> {
+    let destination = match mode {
+        TransparentMode::Tproxy => stream.local_addr()?,
+        TransparentMode::Redirect => {
+            let socket = SockRef::from(stream);
+            let original = match stream.local_addr()? {
+                SocketAddr::V4(_) => socket.original_dst()?,
+                SocketAddr::V6(_) => socket.original_dst_ipv6()?,
+            };
+            original.as_socket().ok_or(anyhow!("original destination is not an IP address"))?
+        }
+    };
+    Ok(destination)
+}
+
+async fn handle(mut stream: TcpStream, peer: SocketAddr, listen: SocketAddr, mode: TransparentMode) -> Result<()> {
+    stream.set_nodelay(true)?;
+
+    let destination = original_destination(&stream, mode)?;
+    if destination.port() == listen.port() && (listen.ip().is_unspecified() || destination.ip() == listen.ip()) {
+        return Err(anyhow!("connection was not redirected"));
+    }
+
+    let host = tokio::time::timeout(PEEK_TIMEOUT, requested_host(&stream)).await
+        .unwrap_or(None)
+        .unwrap_or_else(|| destination.ip().to_string());
+    let authority = relay::authority(&host, destination.port());
+
+    let mut relay = Relay::open(Origin { peer, listener: "transparent", identity: None }).await?;
+    if let Err(e) = relay.connect(&authority).await {
+        warn!("failed to relay transparent connection from {} to {}: {}", peer, authority, e);
+        return Err(e);
+    }
+
+    relay.splice(&mut stream).await
+}
+
+/// Peeks at the first bytes of the connection for the TLS server name or the HTTP `Host` header.
+async fn requested_host(stream: &TcpStream) -> Option<String> {
+    let mut buffer = vec![0; MAX_PEEK];
+    let mut previous = 0;
+    loop {
+        let read = stream.peek(&mut buffer).await.ok()?;
+        let data = &buffer[..read];
+        if read == 0 {
+            return None;
+        }
+
+        if sniff::is_tls(data) {
+            if sniff::tls_record_complete(data) || read == MAX_PEEK {
+                return sniff::server_name(data);
+            }
+        } else if data.windows(4).any(|window| window == b"\r\n\r\n") || read == MAX_PEEK {
+            return sniff::http_host(data);
+        }
+
+        // peek returns immediately while no new data arrived, wait a little before trying again
+        if read == previous {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        previous = read;
+    }
+}
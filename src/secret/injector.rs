@@ -3,56 +3,70 @@ use std::collections::BTreeMap;
 use std::sync::Arc;
 use headers::{Authorization, HeaderMapExt, HeaderName, HeaderValue};
 use hudsucker::hyper::Request;
+use crate::apis::pod_meta::PodMeta;
 use crate::apis::proxy_policy::{
+    PodCertificateSpec,
     ProxyPolicy,
     ProxyPolicyMethod::{
         BasicAuth,
         BearerToken,
         CustomHeader,
         Query,
+        ClientCertificate,
+        PodCertificate,
     },
 };
+use crate::ca::{self, CaMaterial};
 use crate::secret::provider::provider;
+use crate::upstream;
 use anyhow::{anyhow, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
 use hudsucker::Body;
 use hyper::Uri;
+use kube::ResourceExt;
 
 pub trait Injector {
-    fn inject(&self, data: Cow<BTreeMap<String, String>>, request: &mut Request<Body>) -> Result<()>;
+    /// Injects the credentials and returns the values as they are sent upstream, for redaction.
+    fn inject(&self, data: Cow<BTreeMap<String, String>>, request: &mut Request<Body>) -> Result<Vec<String>>;
 }
 
 pub struct BasicAuthInjector {}
 
 impl Injector for BasicAuthInjector {
-    fn inject(&self, data: Cow<BTreeMap<String, String>>, request: &mut Request<Body>) -> Result<()> {
+    fn inject(&self, data: Cow<BTreeMap<String, String>>, request: &mut Request<Body>) -> Result<Vec<String>> {
         let username = data.get("username").ok_or(anyhow!("username required"))?;
         let password = data.get("password").ok_or(anyhow!("password required"))?;
 
-        Ok(request.headers_mut().typed_insert(Authorization::basic(username, password)))
+        request.headers_mut().typed_insert(Authorization::basic(username, password));
+
+        // the header carries base64(username:password), not the secret values
+        Ok(vec![STANDARD.encode(format!("{}:{}", username, password))])
     }
 }
 
 pub struct BearerTokenInjector {}
 
 impl Injector for BearerTokenInjector {
-    fn inject(&self, data: Cow<BTreeMap<String, String>>, request: &mut Request<Body>) -> Result<()> {
+    fn inject(&self, data: Cow<BTreeMap<String, String>>, request: &mut Request<Body>) -> Result<Vec<String>> {
         let token = data.get("token").ok_or(anyhow!("token required"))?;
         let auth = Authorization::bearer(token)?;
+        request.headers_mut().typed_insert(auth);
 
-        Ok(request.headers_mut().typed_insert(auth))
+        Ok(vec![token.clone()])
     }
 }
 
 pub struct CustomHeaderInjector {}
 
 impl Injector for CustomHeaderInjector {
-    fn inject(&self, data: Cow<BTreeMap<String, String>>, request: &mut Request<Body>) -> Result<()> {
+    fn inject(&self, data: Cow<BTreeMap<String, String>>, request: &mut Request<Body>) -> Result<Vec<String>> {
         if let Some((key, value)) = data.first_key_value() {
             let header_key = HeaderName::try_from(key)?;
             let header_value = HeaderValue::try_from(value)?;
             request.headers_mut().insert(header_key, header_value);
 
-            Ok(())
+            Ok(vec![value.clone()])
         } else {
             Err(anyhow!("secret is empty"))
         }
@@ -62,7 +76,7 @@ impl Injector for CustomHeaderInjector {
 pub struct QueryInjector {}
 
 impl Injector for QueryInjector {
-    fn inject(&self, data: Cow<BTreeMap<String, String>>, request: &mut Request<Body>) -> Result<()> {
+    fn inject(&self, data: Cow<BTreeMap<String, String>>, request: &mut Request<Body>) -> Result<Vec<String>> {
         if let Some((key, value)) = data.first_key_value() {
             let uri = request.uri();
             let mut parts = uri.clone().into_parts();
@@ -82,15 +96,52 @@ impl Injector for QueryInjector {
             let new_uri = Uri::from_parts(parts)?;
             *request.uri_mut() = new_uri;
 
-            Ok(())
+            Ok(vec![value.clone()])
         } else {
             Err(anyhow!("secret is empty"))
         }
     }
 }
 
+pub struct ClientCertificateInjector {}
+
+impl Injector for ClientCertificateInjector {
+    fn inject(&self, data: Cow<BTreeMap<String, String>>, request: &mut Request<Body>) -> Result<Vec<String>> {
+        let cert_pem = data.get("tls.crt").ok_or(anyhow!("tls.crt required"))?.clone();
+        let key_pem = data.get("tls.key").ok_or(anyhow!("tls.key required"))?.clone();
+
+        request.extensions_mut().insert(upstream::ClientCertificate { cert_pem, key_pem });
+        Ok(Vec::new())
+    }
+}
+
+/// Mints a certificate for the pod whose `PodMeta` the policy handler attached to the request.
+pub struct PodCertificateInjector {
+    policy: String,
+    spec: PodCertificateSpec,
+}
+
+impl Injector for PodCertificateInjector {
+    fn inject(&self, data: Cow<BTreeMap<String, String>>, request: &mut Request<Body>) -> Result<Vec<String>> {
+        let pod = request.extensions().get::<Arc<PodMeta>>()
+            .ok_or(anyhow!("client is not a known pod"))?
+            .clone();
+        let ca = CaMaterial {
+            key_pem: data.get(ca::KEY_KEY).ok_or(anyhow!("{} required", ca::KEY_KEY))?.clone(),
+            cert_pem: data.get(ca::CERT_KEY).ok_or(anyhow!("{} required", ca::CERT_KEY))?.clone(),
+        };
+
+        let issued = ca::client::pod_certificate(&ca, &pod, &self.spec, &self.policy)?;
+        request.extensions_mut().insert(upstream::ClientCertificate {
+            cert_pem: issued.cert_pem,
+            key_pem: issued.key_pem,
+        });
+        Ok(Vec::new())
+    }
+}
 
-pub async fn inject(request: &mut Request<Body>, policy: &ProxyPolicy) -> Result<()> {
+/// Injects the credentials of the policy and returns the injected values, for redaction.
+pub async fn inject(request: &mut Request<Body>, policy: &ProxyPolicy) -> Result<Vec<String>> {
     let provider = provider(&policy.spec.auth)?;
     let data = provider.secret().await?;
 
@@ -98,7 +149,12 @@ pub async fn inject(request: &mut Request<Body>, policy: &ProxyPolicy) -> Result
         BasicAuth => Arc::new(BasicAuthInjector {}),
         BearerToken => Arc::new(BearerTokenInjector {}),
         CustomHeader => Arc::new(CustomHeaderInjector {}),
-        Query => Arc::new(QueryInjector {})
+        Query => Arc::new(QueryInjector {}),
+        ClientCertificate => Arc::new(ClientCertificateInjector {}),
+        PodCertificate => Arc::new(PodCertificateInjector {
+            policy: format!("{}/{}", policy.namespace().unwrap_or_default(), policy.name_any()),
+            spec: policy.spec.auth.certificate.clone().unwrap_or_default(),
+        }),
     };
 
     injector.inject(data, request)
@@ -4,6 +4,8 @@ use anyhow::{anyhow, Result, Ok};
 use k8s_openapi::api::core::v1::Secret;
 use kube::{Api, Client};
 use crate::apis::proxy_policy::{ProxyPolicyAuth};
+use tracing::{info_span, Instrument};
+use crate::metrics;
 use crate::secret::provider::Provider::{Kubernetes, Raw};
 
 pub enum Provider {
@@ -17,7 +19,16 @@ pub enum Provider {
 impl Provider {
     pub async fn secret(&self) -> Result<Cow<'_, BTreeMap<String, String>>> {
         let data = match self {
-            Kubernetes { namespace, name } => Cow::Owned(kubernetes_secret(namespace, name).await?),
+            Kubernetes { namespace, name } => {
+                let timer = metrics::SECRET_FETCH.with_label_values(&["kubernetes"]).start_timer();
+                let span = info_span!("secret.fetch", provider = "kubernetes", secret = %format!("{}/{}", namespace, name));
+                let result = kubernetes_secret(namespace, name).instrument(span).await;
+                timer.observe_duration();
+                if result.is_err() {
+                    metrics::SECRET_FETCH_ERRORS.with_label_values(&["kubernetes"]).inc();
+                }
+                Cow::Owned(result?)
+            }
             Raw(data) => Cow::Borrowed(data),
         };
         Ok(data)
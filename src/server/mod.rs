@@ -0,0 +1,80 @@
+pub mod tls;
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use anyhow::Result;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::{body::Incoming, Request, StatusCode};
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use log::{debug, error, info};
+use tokio::net::TcpListener;
+use tokio_rustls::{rustls::ServerConfig, TlsAcceptor};
+
+pub type Response = hyper::Response<Full<Bytes>>;
+
+/// Serves `handler` on `addr`, over TLS when a server config is given.
+pub async fn serve<F, Fut>(addr: SocketAddr, tls: Option<Arc<ServerConfig>>, handler: F) -> Result<()>
+where
+    F: Fn(Request<Incoming>) -> Fut + Clone + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send + 'static,
+{
+    let listener = TcpListener::bind(addr).await?;
+    let acceptor = tls.map(TlsAcceptor::from);
+    info!("listening on {}", addr);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("failed to accept connection on {}: {}", addr, e);
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let handler = handler.clone();
+        tokio::spawn(async move {
+            let service = service_fn(move |req| {
+                let handler = handler.clone();
+                async move { Ok::<_, Infallible>(handler(req).await) }
+            });
+
+            let result = match acceptor {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => http1::Builder::new().serve_connection(TokioIo::new(stream), service).await,
+                    Err(e) => {
+                        debug!("TLS handshake with {} failed: {}", peer, e);
+                        return;
+                    }
+                },
+                None => http1::Builder::new().serve_connection(TokioIo::new(stream), service).await,
+            };
+
+            if let Err(e) = result {
+                debug!("connection with {} closed: {}", peer, e);
+            }
+        });
+    }
+}
+
+pub fn response(status: StatusCode, body: impl Into<Bytes>) -> Response {
+    let mut res = Response::new(Full::new(body.into()));
+    *res.status_mut() = status;
+    res
+}
+
+pub fn json_response<T: serde::Serialize>(value: &T) -> Response {
+    match serde_json::to_vec(value) {
+        Ok(body) => {
+            let mut res = response(StatusCode::OK, body);
+            res.headers_mut().insert(hyper::header::CONTENT_TYPE, "application/json".parse().unwrap());
+            res
+        }
+        Err(e) => response(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
@@ -0,0 +1,57 @@
+use std::sync::{Arc, RwLock};
+use anyhow::{anyhow, Result};
+use tokio_rustls::rustls::{
+    crypto::ring::sign::any_supported_type,
+    server::{ClientHello, ResolvesServerCert, danger::ClientCertVerifier},
+    sign::CertifiedKey,
+    ServerConfig,
+};
+
+/// Serves whichever certificate was set last, so certificates can be rotated without
+/// rebuilding listeners.
+#[derive(Debug, Default)]
+pub struct CertResolver {
+    current: RwLock<Option<Arc<CertifiedKey>>>,
+}
+
+impl CertResolver {
+    pub fn set_pem(&self, cert_pem: &[u8], key_pem: &[u8]) -> Result<()> {
+        let certs = rustls_pemfile::certs(&mut &cert_pem[..]).collect::<Result<Vec<_>, _>>()?;
+        if certs.is_empty() {
+            return Err(anyhow!("no certificate found"));
+        }
+        let key = rustls_pemfile::private_key(&mut &key_pem[..])?
+            .ok_or(anyhow!("no private key found"))?;
+        let key = any_supported_type(&key)?;
+
+        *self.current.write().unwrap() = Some(Arc::new(CertifiedKey::new(certs, key)));
+        Ok(())
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.current.read().unwrap().is_some()
+    }
+
+    pub fn server_config(self: &Arc<Self>) -> Arc<ServerConfig> {
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Arc::new(config)
+    }
+
+    /// Like `server_config`, but clients have to present a certificate `verifier` accepts.
+    pub fn server_config_with_client_verifier(self: &Arc<Self>, verifier: Arc<dyn ClientCertVerifier>) -> Arc<ServerConfig> {
+        let mut config = ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Arc::new(config)
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.current.read().unwrap().clone()
+    }
+}
@@ -0,0 +1,54 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use lazy_static::lazy_static;
+use log::info;
+use tokio::sync::watch;
+
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref STOPPED: watch::Sender<bool> = watch::channel(false).0;
+}
+
+/// Resolves on SIGTERM, which Kubernetes sends on pod termination, or on Ctrl-C.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).expect("Failed to install SIGTERM signal handler");
+        tokio::select! {
+            _ = terminate.recv() => info!("received SIGTERM"),
+            _ = tokio::signal::ctrl_c() => info!("received Ctrl-C"),
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to install CTRL+C signal handler");
+    }
+}
+
+/// Waits for a termination signal, reports not ready for `delay` so endpoints can be updated,
+/// then tells listeners to stop accepting connections.
+pub async fn run(delay: Duration) {
+    signal().await;
+
+    info!("shutting down, not ready anymore, accepting connections for another {:?}", delay);
+    DRAINING.store(true, Ordering::Relaxed);
+    tokio::time::sleep(delay).await;
+
+    info!("stopped accepting connections, draining");
+    STOPPED.send_replace(true);
+}
+
+/// Whether a shutdown has started, readiness is reported as false from then on.
+pub fn is_draining() -> bool {
+    DRAINING.load(Ordering::Relaxed)
+}
+
+/// Resolves once listeners should stop accepting connections.
+pub async fn stopped() {
+    let mut stopped = STOPPED.subscribe();
+    let _ = stopped.wait_for(|stopped| *stopped).await;
+}
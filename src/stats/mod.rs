@@ -0,0 +1,230 @@
+pub mod rollup;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+use std::time::Duration;
+use anyhow::Result;
+use crossbeam_skiplist::SkipMap;
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::{Api, Client};
+use kube::api::{ObjectMeta, Patch, PatchParams};
+use lazy_static::lazy_static;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use time::format_description::well_known::Rfc3339;
+use crate::apis::proxy_policy::ProxyPolicyUsage;
+
+lazy_static! {
+    static ref USAGE: SkipMap<String, Mutex<PolicyUsage>> = SkipMap::new();
+}
+
+pub const STATS_LABEL: &str = "auth-bridge.dev/stats";
+pub const USAGE_KEY: &str = "usage.json";
+const MANAGER: &str = "auth-bridge-proxy";
+/// Upper bound of pods remembered per policy, keeps the published ConfigMap small.
+const MAX_PODS: usize = 1_000;
+
+/// Counters of a single policy, as collected by one proxy.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PolicyUsage {
+    pub matches: u64,
+    pub injections: u64,
+    pub denials: u64,
+    pub failures: u64,
+    pub last_used: Option<String>,
+    pub pods: BTreeSet<String>,
+}
+
+impl PolicyUsage {
+    pub fn merge(&mut self, other: &PolicyUsage) {
+        self.matches += other.matches;
+        self.injections += other.injections;
+        self.denials += other.denials;
+        self.failures += other.failures;
+        // RFC 3339 timestamps in UTC sort chronologically
+        if other.last_used > self.last_used {
+            self.last_used = other.last_used.clone();
+        }
+        self.pods.extend(other.pods.iter().cloned());
+    }
+}
+
+impl From<PolicyUsage> for ProxyPolicyUsage {
+    fn from(usage: PolicyUsage) -> Self {
+        ProxyPolicyUsage {
+            matches: usage.matches,
+            injections: usage.injections,
+            denials: usage.denials,
+            failures: usage.failures,
+            last_used: usage.last_used,
+            distinct_pods: usage.pods.len() as u64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Event {
+    /// every rule of the policy allowed the request
+    Matched,
+    /// credentials were injected
+    Injected,
+    /// a rule of the policy rejected the request
+    Denied,
+    /// evaluating the policy or injecting credentials failed
+    Failed,
+}
+
+pub fn policy_key(namespace: &str, name: &str) -> String {
+    format!("{}/{}", namespace, name)
+}
+
+pub fn record(policy: &str, event: Event, pod: Option<&str>) {
+    let entry = USAGE.get_or_insert_with(policy.to_string(), || Mutex::new(PolicyUsage::default()));
+    let mut usage = entry.value().lock().unwrap();
+
+    match event {
+        Event::Matched => usage.matches += 1,
+        Event::Denied => usage.denials += 1,
+        Event::Failed => usage.failures += 1,
+        Event::Injected => {
+            usage.injections += 1;
+            usage.last_used = OffsetDateTime::now_utc().format(&Rfc3339).ok();
+            if let Some(pod) = pod {
+                if usage.pods.len() < MAX_PODS || usage.pods.contains(pod) {
+                    usage.pods.insert(pod.to_string());
+                }
+            }
+        }
+    }
+}
+
+pub fn snapshot() -> BTreeMap<String, PolicyUsage> {
+    USAGE.iter()
+        .map(|entry| (entry.key().clone(), entry.value().lock().unwrap().clone()))
+        .collect()
+}
+
+/// Adds the published counts to those recorded since startup.
+fn restore(usage: BTreeMap<String, PolicyUsage>) {
+    for (policy, usage) in usage {
+        let entry = USAGE.get_or_insert_with(policy, || Mutex::new(PolicyUsage::default()));
+        entry.value().lock().unwrap().merge(&usage);
+    }
+}
+
+pub fn configmap_name(node: &str) -> String {
+    format!("auth-bridge-stats-{}", node)
+}
+
+/// Periodically publishes the counters of this proxy as a ConfigMap named after the node.
+///
+/// Counters already published by a previous run on the same node are restored first, so
+/// they keep growing across restarts.
+pub async fn publish(client: Client, namespace: String, node: String, interval: Duration) {
+    let api = Api::<ConfigMap>::namespaced(client, &namespace);
+    let name = configmap_name(&node);
+
+    match api.get_opt(&name).await {
+        Ok(Some(configmap)) => {
+            let usage = configmap.data.as_ref().and_then(|data| data.get(USAGE_KEY));
+            match usage.map(|usage| serde_json::from_str(usage)) {
+                Some(Ok(usage)) => restore(usage),
+                Some(Err(e)) => error!("failed to restore usage statistics from {}/{}: {}", namespace, name, e),
+                None => {}
+            }
+        }
+        Ok(None) => {}
+        Err(e) => error!("failed to read usage statistics {}/{}: {}", namespace, name, e),
+    }
+
+    let mut interval = tokio::time::interval(interval);
+    let mut published = BTreeMap::new();
+    loop {
+        interval.tick().await;
+
+        let usage = snapshot();
+        if usage == published {
+            continue;
+        }
+
+        match write(&api, &name, &node, &usage).await {
+            Ok(_) => published = usage,
+            Err(e) => error!("failed to publish usage statistics to {}/{}: {}", namespace, name, e),
+        }
+    }
+}
+
+async fn write(api: &Api<ConfigMap>, name: &str, node: &str, usage: &BTreeMap<String, PolicyUsage>) -> Result<()> {
+    let configmap = ConfigMap {
+        metadata: ObjectMeta {
+            name: Some(name.to_string()),
+            labels: Some(BTreeMap::from([
+                (STATS_LABEL.to_string(), "true".to_string()),
+                ("auth-bridge.dev/node".to_string(), node.to_string()),
+            ])),
+            ..ObjectMeta::default()
+        },
+        data: Some(BTreeMap::from([(USAGE_KEY.to_string(), serde_json::to_string(usage)?)])),
+        ..ConfigMap::default()
+    };
+
+    api.patch(name, &PatchParams::apply(MANAGER).force(), &Patch::Apply(&configmap)).await?;
+    info!("published usage statistics of {} policies", usage.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(counts: [u64; 4], last_used: Option<&str>, pods: &[&str]) -> PolicyUsage {
+        PolicyUsage {
+            matches: counts[0],
+            injections: counts[1],
+            denials: counts[2],
+            failures: counts[3],
+            last_used: last_used.map(String::from),
+            pods: pods.iter().map(|pod| pod.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn merge() {
+        let cases = [
+            (
+                usage([1, 2, 3, 4], Some("2024-01-01T00:00:00Z"), &["a/x"]),
+                usage([10, 20, 30, 40], Some("2024-02-01T00:00:00Z"), &["a/x", "b/y"]),
+                usage([11, 22, 33, 44], Some("2024-02-01T00:00:00Z"), &["a/x", "b/y"]),
+            ),
+            (
+                usage([1, 1, 0, 0], Some("2024-02-01T00:00:00Z"), &["a/x"]),
+                usage([0, 0, 1, 0], Some("2024-01-01T00:00:00Z"), &[]),
+                usage([1, 1, 1, 0], Some("2024-02-01T00:00:00Z"), &["a/x"]),
+            ),
+            (
+                usage([0, 0, 0, 0], None, &[]),
+                usage([1, 1, 0, 0], Some("2024-01-01T00:00:00Z"), &["a/x"]),
+                usage([1, 1, 0, 0], Some("2024-01-01T00:00:00Z"), &["a/x"]),
+            ),
+            (
+                usage([1, 1, 0, 0], Some("2024-01-01T00:00:00Z"), &["a/x"]),
+                usage([0, 0, 0, 1], None, &[]),
+                usage([1, 1, 0, 1], Some("2024-01-01T00:00:00Z"), &["a/x"]),
+            ),
+        ];
+        for (mut merged, other, expected) in cases {
+            merged.merge(&other);
+            assert_eq!(merged, expected);
+        }
+    }
+
+    #[test]
+    fn counts_distinct_pods_in_the_status() {
+        let status = ProxyPolicyUsage::from(usage([1, 2, 3, 4], Some("2024-01-01T00:00:00Z"), &["a/x", "b/y"]));
+        assert_eq!(status.injections, 2);
+        assert_eq!(status.distinct_pods, 2);
+        assert_eq!(status.last_used.as_deref(), Some("2024-01-01T00:00:00Z"));
+    }
+}
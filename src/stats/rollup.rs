@@ -0,0 +1,108 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+use anyhow::Result;
+use k8s_openapi::api::core::v1::ConfigMap;
+use kube::{Api, Client, ResourceExt};
+use kube::api::{ListParams, Patch, PatchParams};
+use log::{debug, error};
+use serde_json::json;
+use crate::apis::proxy_policy::{ProxyPolicy, ProxyPolicyUsage};
+use crate::metrics;
+use crate::stats::{policy_key, PolicyUsage, STATS_LABEL, USAGE_KEY};
+
+/// Sums the counters published by every proxy into the status of each ProxyPolicy.
+pub async fn run(client: Client, namespace: String, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let result = rollup(&client, &namespace).await;
+        metrics::reconciled("stats-rollup", &result);
+        if let Err(e) = result {
+            error!("failed to roll up usage statistics: {}", e);
+        }
+    }
+}
+
+async fn rollup(client: &Client, namespace: &str) -> Result<()> {
+    let configmaps = Api::<ConfigMap>::namespaced(client.clone(), namespace);
+    let params = ListParams::default().labels(&format!("{}=true", STATS_LABEL));
+
+    let totals = totals(&configmaps.list(&params).await?.items);
+
+    let policies = Api::<ProxyPolicy>::all(client.clone());
+    for policy in policies.list(&ListParams::default()).await? {
+        let namespace = policy.namespace().unwrap_or_default();
+        let name = policy.name_any();
+        let usage = ProxyPolicyUsage::from(totals.get(&policy_key(&namespace, &name)).cloned().unwrap_or_default());
+
+        let current = policy.status.as_ref().and_then(|status| status.usage.as_ref());
+        if current == Some(&usage) {
+            continue;
+        }
+
+        let api = Api::<ProxyPolicy>::namespaced(client.clone(), &namespace);
+        let patch = json!({ "status": { "usage": usage } });
+        match api.patch_status(&name, &PatchParams::default(), &Patch::Merge(&patch)).await {
+            Ok(_) => debug!("updated usage of policy {}/{}", namespace, name),
+            Err(e) => error!("failed to update usage of policy {}/{}: {}", namespace, name, e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Sums the counters published in the statistics ConfigMaps, by policy.
+fn totals(configmaps: &[ConfigMap]) -> BTreeMap<String, PolicyUsage> {
+    let mut totals: BTreeMap<String, PolicyUsage> = BTreeMap::new();
+    for configmap in configmaps {
+        let Some(data) = configmap.data.as_ref().and_then(|data| data.get(USAGE_KEY)) else {
+            continue;
+        };
+        let usage: BTreeMap<String, PolicyUsage> = match serde_json::from_str(data) {
+            Ok(usage) => usage,
+            Err(e) => {
+                error!("invalid usage statistics in {}: {}", configmap.name_any(), e);
+                continue;
+            }
+        };
+        for (policy, usage) in usage {
+            totals.entry(policy).or_default().merge(&usage);
+        }
+    }
+    totals
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kube::api::ObjectMeta;
+
+    fn configmap(name: &str, usage: Option<&str>) -> ConfigMap {
+        ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                ..ObjectMeta::default()
+            },
+            data: usage.map(|usage| BTreeMap::from([(USAGE_KEY.to_string(), usage.to_string())])),
+            ..ConfigMap::default()
+        }
+    }
+
+    #[test]
+    fn sums_every_node() {
+        let configmaps = [
+            configmap("auth-bridge-stats-a", Some(r#"{"ns/p": {"matches": 2, "injections": 2, "denials": 0, "failures": 1, "lastUsed": "2024-01-01T00:00:00Z", "pods": ["ns/x"]}}"#)),
+            configmap("auth-bridge-stats-b", Some(r#"{"ns/p": {"matches": 1, "injections": 1, "denials": 3, "failures": 0, "lastUsed": "2024-02-01T00:00:00Z", "pods": ["ns/x", "ns/y"]},
+                                                  "ns/q": {"matches": 5, "injections": 5, "denials": 0, "failures": 0, "lastUsed": null, "pods": []}}"#)),
+            configmap("auth-bridge-stats-c", Some("not json")),
+            configmap("auth-bridge-stats-d", None),
+        ];
+
+        let totals = totals(&configmaps);
+        assert_eq!(totals.len(), 2);
+        let p = ProxyPolicyUsage::from(totals["ns/p"].clone());
+        assert_eq!((p.matches, p.injections, p.denials, p.failures, p.distinct_pods), (3, 3, 3, 1, 2));
+        assert_eq!(p.last_used.as_deref(), Some("2024-02-01T00:00:00Z"));
+        assert_eq!(totals["ns/q"].matches, 5);
+    }
+}
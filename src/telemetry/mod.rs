@@ -0,0 +1,122 @@
+use anyhow::Result;
+use hudsucker::hyper::{header::HeaderName, HeaderMap, Method, Request, StatusCode};
+use hudsucker::Body;
+use opentelemetry::{global, KeyValue};
+use opentelemetry::propagation::{Extractor, Injector};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace, Resource};
+use tracing::{info_span, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+/// Installs the log output and, when an OTLP endpoint is given, the trace exporter.
+pub fn init(otlp_endpoint: Option<&str>, service_name: &'static str) -> Result<()> {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer());
+
+    match otlp_endpoint {
+        Some(endpoint) => {
+            global::set_text_map_propagator(TraceContextPropagator::new());
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .tracing()
+                .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint))
+                .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                    KeyValue::new("service.name", service_name),
+                ])))
+                .install_batch(runtime::Tokio)?;
+
+            registry.with(tracing_opentelemetry::layer().with_tracer(tracer)).init();
+        }
+        None => registry.init(),
+    }
+
+    Ok(())
+}
+
+/// Flushes spans that have not been exported yet.
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+}
+
+/// Spans of one proxied request: `request` covers the whole exchange and continues the trace
+/// of an incoming `traceparent`, `upstream` covers the call to the upstream server.
+#[derive(Clone)]
+pub struct RequestTrace {
+    pub request: Span,
+    upstream: Option<Span>,
+}
+
+impl RequestTrace {
+    /// Returns `None` for CONNECT requests, their tunnels are traced per inner request.
+    pub fn start(req: &Request<Body>) -> Option<Self> {
+        if req.method() == Method::CONNECT {
+            return None;
+        }
+
+        let request = info_span!(
+            "proxy.request",
+            otel.kind = "server",
+            http.method = %req.method(),
+            http.url = %req.uri().path(),
+            http.host = req.uri().host().unwrap_or_default(),
+        );
+        let parent = global::get_text_map_propagator(|propagator| {
+            propagator.extract(&HeaderExtractor(req.headers()))
+        });
+        request.set_parent(parent);
+
+        Some(RequestTrace { request, upstream: None })
+    }
+
+    /// Opens the upstream span as the request leaves the handlers and replaces the incoming trace
+    /// context with its own, so the upstream server continues the trace below the proxy.
+    ///
+    /// hudsucker makes the upstream call itself, the span is closed by `finish` once it returned.
+    pub fn forward(&mut self, headers: &mut HeaderMap) {
+        let upstream = info_span!(
+            parent: &self.request,
+            "proxy.upstream",
+            otel.kind = "client",
+            http.status_code = tracing::field::Empty,
+        );
+        let context = upstream.context();
+        global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&context, &mut HeaderInjector(headers))
+        });
+        self.upstream = Some(upstream);
+    }
+
+    pub fn finish(self, status: Option<StatusCode>) {
+        let Some(upstream) = self.upstream else {
+            return;
+        };
+        match status {
+            Some(status) => upstream.record("http.status_code", status.as_u16()),
+            None => upstream.record("http.status_code", "error"),
+        };
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (HeaderName::try_from(key), value.parse()) {
+            self.0.insert(name, value);
+        }
+    }
+}
@@ -0,0 +1,233 @@
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::task::{Context, Poll};
+use anyhow::{anyhow, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use futures::StreamExt;
+use hyper::Uri;
+use hyper_util::rt::TokioIo;
+use ipnet::IpNet;
+use k8s_openapi::api::core::v1::Secret;
+use kube::{Api, Client};
+use kube::runtime::{watcher, WatchStreamExt};
+use log::{debug, error, info};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tower_service::Service;
+
+/// Upper bound of the response header a parent proxy may send for a CONNECT.
+const MAX_CONNECT_RESPONSE: usize = 8 * 1024;
+
+/// A corporate proxy upstream traffic is tunneled through with HTTP CONNECT.
+pub struct ParentProxy {
+    host: String,
+    port: u16,
+    no_proxy: Vec<NoProxy>,
+    authorization: RwLock<Option<String>>,
+}
+
+impl ParentProxy {
+    pub fn new(url: &str, no_proxy: &[String]) -> Result<Self> {
+        let uri: Uri = url.parse()?;
+        // the hop to the parent proxy is plain TCP, an https:// proxy would silently get cleartext
+        match uri.scheme_str() {
+            None | Some("http") => {}
+            Some(scheme) => return Err(anyhow!("upstream proxy {} uses {}://, only http:// is supported", url, scheme)),
+        }
+        let host = uri.host().ok_or(anyhow!("upstream proxy {} has no host", url))?.to_string();
+        let port = uri.port_u16().unwrap_or(80);
+
+        Ok(ParentProxy {
+            host,
+            port,
+            no_proxy: no_proxy.iter().map(|entry| NoProxy::parse(entry)).collect(),
+            authorization: RwLock::new(None),
+        })
+    }
+
+    pub fn set_credentials(&self, username: &str, password: &str) {
+        let token = STANDARD.encode(format!("{}:{}", username, password));
+        *self.authorization.write().unwrap() = Some(format!("Basic {}", token));
+    }
+
+    /// Whether `host` is reached directly because of the `NO_PROXY` list.
+    pub fn bypass(&self, host: &str) -> bool {
+        self.no_proxy.iter().any(|entry| entry.matches(host))
+    }
+
+    /// Opens a CONNECT tunnel to `host:port` through the parent proxy.
+    pub async fn tunnel(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+
+        let mut request = format!("CONNECT {host}:{port} HTTP/1.1\r\nHost: {host}:{port}\r\n");
+        if let Some(authorization) = self.authorization.read().unwrap().as_ref() {
+            request.push_str(&format!("Proxy-Authorization: {}\r\n", authorization));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes()).await?;
+
+        // read byte by byte so nothing after the header is consumed
+        let mut response = Vec::new();
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() >= MAX_CONNECT_RESPONSE {
+                return Err(anyhow!("response of upstream proxy is too large"));
+            }
+            response.push(stream.read_u8().await?);
+        }
+
+        let status_line = String::from_utf8_lossy(&response);
+        let status = status_line.split_whitespace().nth(1).unwrap_or_default();
+        if status != "200" {
+            return Err(anyhow!("upstream proxy refused CONNECT to {}:{} with status {}", host, port, status));
+        }
+
+        Ok(stream)
+    }
+}
+
+/// A `NO_PROXY` entry: `*`, a host, a domain suffix such as `.corp.example`, an IP or a CIDR.
+enum NoProxy {
+    Any,
+    Net(IpNet),
+    Ip(IpAddr),
+    Domain(String),
+}
+
+impl NoProxy {
+    fn parse(entry: &str) -> Self {
+        let entry = entry.trim().to_lowercase();
+        if entry == "*" {
+            NoProxy::Any
+        } else if let Ok(net) = entry.parse::<IpNet>() {
+            NoProxy::Net(net)
+        } else if let Ok(ip) = entry.parse::<IpAddr>() {
+            NoProxy::Ip(ip)
+        } else {
+            NoProxy::Domain(entry.trim_start_matches('.').to_string())
+        }
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        let host = host.trim_start_matches('[').trim_end_matches(']').to_lowercase();
+        match self {
+            NoProxy::Any => true,
+            NoProxy::Net(net) => host.parse::<IpAddr>().map(|ip| net.contains(&ip)).unwrap_or(false),
+            NoProxy::Ip(ip) => host.parse::<IpAddr>().map(|host| host == *ip).unwrap_or(false),
+            NoProxy::Domain(domain) => host == *domain || host.ends_with(&format!(".{}", domain)),
+        }
+    }
+}
+
+/// Connects directly, or through the parent proxy when one is configured and the host is not
+/// exempted by its `NO_PROXY` list.
+#[derive(Clone, Default)]
+pub struct ParentProxyConnector {
+    parent: Option<Arc<ParentProxy>>,
+}
+
+impl ParentProxyConnector {
+    pub fn new(parent: Option<Arc<ParentProxy>>) -> Self {
+        ParentProxyConnector { parent }
+    }
+}
+
+impl Service<Uri> for ParentProxyConnector {
+    type Response = TokioIo<TcpStream>;
+    type Error = anyhow::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let parent = self.parent.clone();
+        Box::pin(async move {
+            let host = uri.host().ok_or(anyhow!("uri {} has no host", uri))?.to_string();
+            let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+                Some("https") | Some("wss") => 443,
+                _ => 80,
+            });
+
+            let stream = match parent {
+                Some(parent) if !parent.bypass(&host) => {
+                    debug!("tunneling to {}:{} through the upstream proxy", host, port);
+                    parent.tunnel(&host, port).await?
+                }
+                _ => TcpStream::connect((host.trim_start_matches('[').trim_end_matches(']'), port)).await?,
+            };
+            stream.set_nodelay(true)?;
+
+            Ok(TokioIo::new(stream))
+        })
+    }
+}
+
+/// Keeps the parent proxy credentials in sync with a Secret holding `username` and `password`.
+pub async fn watch_credentials(client: Client, namespace: String, name: String, parent: Arc<ParentProxy>) {
+    let api = Api::<Secret>::namespaced(client, &namespace);
+    let wc = watcher::Config::default().fields(&format!("metadata.name={}", name));
+
+    let mut stream = watcher(api, wc).applied_objects().boxed();
+    while let Some(event) = stream.next().await {
+        let secret = match event {
+            Ok(secret) => secret,
+            Err(e) => {
+                error!("upstream proxy secret watch error: {}", e);
+                continue;
+            }
+        };
+
+        let data = secret.data.unwrap_or_default();
+        let value = |key: &str| data.get(key).map(|value| String::from_utf8_lossy(&value.0).into_owned());
+        match (value("username"), value("password")) {
+            (Some(username), Some(password)) => {
+                parent.set_credentials(&username, &password);
+                info!("loaded upstream proxy credentials from {}/{}", namespace, name);
+            }
+            _ => error!("secret {}/{} must contain username and password", namespace, name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bypasses_no_proxy_entries() {
+        let no_proxy: Vec<String> = ["10.0.0.0/8", "fd00::/8", "192.168.1.10", ".corp.example", "localhost"]
+            .iter().map(|entry| entry.to_string()).collect();
+        let parent = ParentProxy::new("http://proxy.corp.example:3128", &no_proxy).unwrap();
+
+        let cases = [
+            ("10.1.2.3", true),
+            ("10.255.255.255", true),
+            ("11.0.0.1", false),
+            ("[fd00::1]", true),
+            ("fd00::1", true),
+            ("fe80::1", false),
+            ("192.168.1.10", true),
+            ("192.168.1.11", false),
+            ("corp.example", true),
+            ("git.corp.example", true),
+            ("GIT.Corp.Example", true),
+            ("notcorp.example", false),
+            ("localhost", true),
+            ("example.com", false),
+        ];
+        for (host, bypassed) in cases {
+            assert_eq!(parent.bypass(host), bypassed, "{}", host);
+        }
+    }
+
+    #[test]
+    fn bypasses_everything_for_a_wildcard() {
+        let parent = ParentProxy::new("http://proxy:3128", &["*".to_string()]).unwrap();
+        assert!(parent.bypass("example.com"));
+        assert!(parent.bypass("10.0.0.1"));
+    }
+}
@@ -0,0 +1,161 @@
+pub mod connector;
+pub mod tls;
+
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use hudsucker::Body;
+use hudsucker::tokio_tungstenite::Connector;
+use hyper::{header, Request, Version};
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use moka::future::Cache;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio_rustls::rustls::ClientConfig;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use self::connector::{ParentProxy, ParentProxyConnector};
+use self::tls::UpstreamVerifier;
+
+/// Number of client identities whose connection pools are kept.
+const MAX_IDENTITIES: u64 = 1_000;
+/// How long the pool of an unused client identity is kept.
+const IDENTITY_IDLE: Duration = Duration::from_secs(10 * 60);
+
+pub type UpstreamClient = Client<HttpsConnector<ParentProxyConnector>, Body>;
+
+/// A parent proxy upstream traffic is sent through.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ParentProxyConfig {
+    /// url of the parent proxy, e.g. http://proxy.corp.example:3128
+    pub url: String,
+    /// hosts, domain suffixes, IPs and CIDRs that are reached directly
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub no_proxy: Vec<String>,
+    /// secret with `username` and `password` for the parent proxy, as <namespace>/<name>
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credentials_secret: Option<String>,
+}
+
+/// A client certificate presented to the upstream, attached to a request as an extension by the injector.
+#[derive(Clone)]
+pub struct ClientCertificate {
+    pub cert_pem: String,
+    pub key_pem: String,
+}
+
+impl ClientCertificate {
+    fn fingerprint(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(&self.cert_pem);
+        hasher.update(&self.key_pem);
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn parse(&self) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+        let certs = rustls_pemfile::certs(&mut self.cert_pem.as_bytes()).collect::<Result<Vec<_>, _>>()?;
+        let key = rustls_pemfile::private_key(&mut self.key_pem.as_bytes())?
+            .ok_or(anyhow!("client certificate has no private key"))?;
+        Ok((certs, key))
+    }
+}
+
+/// The clients intercepted requests are forwarded with.
+///
+/// Requests carrying a `ClientCertificate` get a client of their own per certificate, so connections
+/// authenticated with a certificate are never reused for requests of another identity.
+#[derive(Clone)]
+pub struct Upstream {
+    parent: Option<Arc<ParentProxy>>,
+    verifier: Arc<UpstreamVerifier>,
+    identities: Cache<String, UpstreamClient>,
+}
+
+impl Upstream {
+    pub fn new(parent: Option<Arc<ParentProxy>>, verifier: Arc<UpstreamVerifier>) -> Self {
+        Upstream {
+            parent,
+            verifier,
+            identities: Cache::builder()
+                .max_capacity(MAX_IDENTITIES)
+                .time_to_idle(IDENTITY_IDLE)
+                .build(),
+        }
+    }
+
+    /// The client for requests without a client certificate.
+    pub fn client(&self) -> UpstreamClient {
+        build_client(self.parent.clone(), self.verifier.client_config(), true)
+    }
+
+    /// The parent proxy connections to `host` go through, `None` when `host` is reached directly.
+    pub fn parent_for(&self, host: &str) -> Option<Arc<ParentProxy>> {
+        self.parent.clone().filter(|parent| !parent.bypass(host))
+    }
+
+    /// The connector WebSocket connections to upstreams are opened with, verifying servers like `client`.
+    pub fn websocket_connector(&self) -> Connector {
+        Connector::Rustls(Arc::new(self.verifier.client_config()))
+    }
+
+    /// The client presenting `certificate` to upstreams.
+    pub async fn client_with_certificate(&self, certificate: &ClientCertificate) -> Result<UpstreamClient> {
+        let fingerprint = certificate.fingerprint();
+        if let Some(client) = self.identities.get(&fingerprint).await {
+            return Ok(client);
+        }
+
+        let (certs, key) = certificate.parse()?;
+        let tls = self.verifier.client_config_with_certificate(certs, key)?;
+
+        let client = build_client(self.parent.clone(), tls, true);
+        self.identities.insert(fingerprint, client.clone()).await;
+        Ok(client)
+    }
+
+    /// A client for WebSocket handshakes hudsucker cannot open itself, through the parent proxy or
+    /// with a client certificate. It only speaks HTTP/1.1 so the connection can be upgraded.
+    pub fn upgrade_client(&self, certificate: Option<&ClientCertificate>) -> Result<UpstreamClient> {
+        let tls = match certificate {
+            Some(certificate) => {
+                let (certs, key) = certificate.parse()?;
+                self.verifier.client_config_with_certificate(certs, key)?
+            }
+            None => self.verifier.client_config(),
+        };
+        Ok(build_client(self.parent.clone(), tls, false))
+    }
+}
+
+/// Prepares a request for the HTTP/1.1 upstream client the same way hudsucker does.
+pub fn normalize(mut req: Request<Body>) -> Request<Body> {
+    req.headers_mut().remove(header::HOST);
+
+    // HTTP/2 allows several cookie headers, HTTP/1.1 only one
+    let cookies: Vec<String> = req.headers().get_all(header::COOKIE).iter()
+        .filter_map(|value| value.to_str().ok().map(str::to_string))
+        .collect();
+    if cookies.len() > 1 {
+        if let Ok(value) = cookies.join("; ").parse() {
+            req.headers_mut().insert(header::COOKIE, value);
+        }
+    }
+    *req.version_mut() = Version::HTTP_11;
+    req
+}
+
+fn build_client(parent: Option<Arc<ParentProxy>>, tls: ClientConfig, http2: bool) -> UpstreamClient {
+    let builder = HttpsConnectorBuilder::new()
+        .with_tls_config(tls)
+        .https_or_http()
+        .enable_http1();
+    let https = if http2 {
+        builder.enable_http2().wrap_connector(ParentProxyConnector::new(parent))
+    } else {
+        builder.wrap_connector(ParentProxyConnector::new(parent))
+    };
+
+    Client::builder(TokioExecutor::new()).build(https)
+}
@@ -0,0 +1,306 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+use anyhow::{anyhow, Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use k8s_openapi::api::core::v1::Secret;
+use kube::{Api, Client};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio_rustls::rustls::{ClientConfig, DigitallySignedStruct, Error, RootCertStore, SignatureScheme};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use crate::config::hosts;
+use crate::metrics;
+
+/// Key of the CA certificates in a trust bundle Secret.
+const CA_KEY: &str = "ca.crt";
+const PIN_PREFIX: &str = "sha256/";
+
+/// CA certificates trusted in addition to the public web roots.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TrustConfig {
+    /// PEM files with CA certificates
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ca_files: Vec<PathBuf>,
+    /// secrets with CA certificates under `ca.crt`, as <namespace>/<name>
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ca_secrets: Vec<String>,
+}
+
+/// How the certificates of upstream servers are verified.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UpstreamTlsConfig {
+    /// CAs trusted for every upstream
+    #[serde(flatten)]
+    pub trust: TrustConfig,
+    /// settings of the upstreams matching a host pattern, the first matching entry wins
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hosts: Vec<HostTlsConfig>,
+}
+
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct HostTlsConfig {
+    /// host pattern, e.g. `gitlab.corp.example` or `*.lab.example`
+    pub host: String,
+    /// CAs trusted for these hosts in addition to the global ones
+    #[serde(flatten)]
+    pub trust: TrustConfig,
+    /// accept any certificate, every such connection is logged and counted
+    #[serde(default)]
+    pub insecure_skip_verify: bool,
+    /// SHA-256 hashes of the accepted public keys, as `sha256/<base64>`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pins: Vec<String>,
+}
+
+/// Verifies upstream certificates against the configured roots, pins and exceptions.
+pub struct UpstreamVerifier {
+    default: Arc<WebPkiServerVerifier>,
+    hosts: Vec<HostVerifier>,
+}
+
+struct HostVerifier {
+    pattern: String,
+    /// `None` when verification is skipped
+    verifier: Option<Arc<WebPkiServerVerifier>>,
+    pins: Vec<Vec<u8>>,
+}
+
+impl UpstreamVerifier {
+    pub async fn load(config: &UpstreamTlsConfig) -> Result<Self> {
+        let global = load_certificates(&config.trust).await?;
+        let default = build_verifier(&global)?;
+
+        let mut hosts = Vec::new();
+        for host in &config.hosts {
+            let verifier = if host.insecure_skip_verify {
+                warn!("certificate verification is disabled for upstreams matching {}", host.host);
+                None
+            } else if host.trust == TrustConfig::default() {
+                Some(default.clone())
+            } else {
+                let mut certificates = global.clone();
+                certificates.extend(load_certificates(&host.trust).await?);
+                Some(build_verifier(&certificates)?)
+            };
+
+            let pins = host.pins.iter()
+                .map(|pin| STANDARD.decode(pin.strip_prefix(PIN_PREFIX).unwrap_or(pin)))
+                .collect::<Result<Vec<_>, _>>()
+                .with_context(|| format!("invalid pin for {}", host.host))?;
+
+            hosts.push(HostVerifier { pattern: host.host.clone(), verifier, pins });
+        }
+
+        Ok(UpstreamVerifier { default, hosts })
+    }
+
+    /// A rustls client config verifying servers with this verifier.
+    pub fn client_config(self: &Arc<Self>) -> ClientConfig {
+        ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(self.clone())
+            .with_no_client_auth()
+    }
+
+    /// A rustls client config verifying servers with this verifier and presenting a client certificate.
+    pub fn client_config_with_certificate(
+        self: &Arc<Self>,
+        certs: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> Result<ClientConfig> {
+        Ok(ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(self.clone())
+            .with_client_auth_cert(certs, key)?)
+    }
+}
+
+impl ServerCertVerifier for UpstreamVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let name = match server_name {
+            ServerName::DnsName(name) => name.as_ref().to_string(),
+            ServerName::IpAddress(ip) => std::net::IpAddr::from(*ip).to_string(),
+            _ => String::new(),
+        };
+
+        let host = self.hosts.iter().find(|host| hosts::matches(&host.pattern, &name));
+        let verified = match host {
+            Some(HostVerifier { verifier: None, pattern, .. }) => {
+                warn!("skipped certificate verification of upstream {}", name);
+                metrics::UPSTREAM_TLS_INSECURE.with_label_values(&[pattern]).inc();
+                ServerCertVerified::assertion()
+            }
+            Some(HostVerifier { verifier: Some(verifier), .. }) => verifier.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?,
+            None => self.default.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?,
+        };
+
+        if let Some(host) = host.filter(|host| !host.pins.is_empty()) {
+            let hash = spki_hash(end_entity)?;
+            if !host.pins.contains(&hash) {
+                warn!("public key of upstream {} does not match any pin", name);
+                return Err(Error::General(format!("public key of {} is not pinned", name)));
+            }
+        }
+
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.default.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.default.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.default.supported_verify_schemes()
+    }
+}
+
+impl fmt::Debug for UpstreamVerifier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("UpstreamVerifier")
+            .field("hosts", &self.hosts.iter().map(|host| &host.pattern).collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+fn build_verifier(certificates: &[CertificateDer<'static>]) -> Result<Arc<WebPkiServerVerifier>> {
+    let mut roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let (added, ignored) = roots.add_parsable_certificates(certificates.iter().cloned());
+    if ignored > 0 {
+        warn!("ignored {} invalid upstream CA certificates", ignored);
+    }
+    if added > 0 {
+        info!("trusting {} additional upstream CA certificates", added);
+    }
+
+    Ok(WebPkiServerVerifier::builder(Arc::new(roots)).build()?)
+}
+
+pub async fn load_certificates(trust: &TrustConfig) -> Result<Vec<CertificateDer<'static>>> {
+    let mut pems = Vec::new();
+    for path in &trust.ca_files {
+        let pem = std::fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+        pems.push(pem);
+    }
+
+    if !trust.ca_secrets.is_empty() {
+        let client = Client::try_default().await?;
+        for secret in &trust.ca_secrets {
+            let (namespace, name) = secret.split_once('/')
+                .ok_or(anyhow!("CA secret {} must be in the form <namespace>/<name>", secret))?;
+            let secret = Api::<Secret>::namespaced(client.clone(), namespace).get(name).await?;
+            let pem = secret.data.unwrap_or_default().remove(CA_KEY)
+                .ok_or(anyhow!("secret {}/{} has no {}", namespace, name, CA_KEY))?;
+            pems.push(pem.0);
+        }
+    }
+
+    let mut certificates = Vec::new();
+    for pem in pems {
+        for cert in rustls_pemfile::certs(&mut pem.as_slice()) {
+            certificates.push(cert?);
+        }
+    }
+    Ok(certificates)
+}
+
+fn spki_hash(cert: &CertificateDer<'_>) -> Result<Vec<u8>, Error> {
+    let (_, parsed) = x509_parser::parse_x509_certificate(cert.as_ref())
+        .map_err(|e| Error::General(format!("failed to parse upstream certificate: {}", e)))?;
+    Ok(Sha256::digest(parsed.tbs_certificate.subject_pki.raw).to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hudsucker::rcgen::CertificateParams;
+    use crate::ca::{CaMaterial, KeyAlgorithm};
+
+    fn certificate(ca: &CaMaterial, host: &str) -> CertificateDer<'static> {
+        let params = CertificateParams::new(vec![host.to_string()]).unwrap();
+        let issued = ca.issue(params, std::time::Duration::from_secs(3600)).unwrap();
+        let cert = rustls_pemfile::certs(&mut issued.cert_pem.as_bytes()).next().unwrap().unwrap();
+        cert
+    }
+
+    fn pin(cert: &CertificateDer<'_>) -> String {
+        format!("{}{}", PIN_PREFIX, STANDARD.encode(spki_hash(cert).unwrap()))
+    }
+
+    #[tokio::test]
+    async fn verifies_pins() {
+        let ca = CaMaterial::generate(KeyAlgorithm::EcdsaP256, std::time::Duration::from_secs(3600)).unwrap();
+        let ca_file = std::env::temp_dir().join(format!("auth-bridge-pins-{}.crt", std::process::id()));
+        std::fs::write(&ca_file, &ca.cert_pem).unwrap();
+
+        let pinned = certificate(&ca, "pinned.example");
+        let other = certificate(&ca, "pinned.example");
+        let host = |pins: Vec<String>, insecure_skip_verify: bool| HostTlsConfig {
+            host: "pinned.example".to_string(),
+            trust: TrustConfig { ca_files: vec![ca_file.clone()], ca_secrets: Vec::new() },
+            insecure_skip_verify,
+            pins,
+        };
+
+        let cases = [
+            ("trusted without pins", host(Vec::new(), false), true),
+            ("trusted and pinned", host(vec![pin(&pinned)], false), true),
+            ("one of several pins", host(vec![pin(&other), pin(&pinned)], false), true),
+            ("trusted but another key pinned", host(vec![pin(&other)], false), false),
+            ("insecure but another key pinned", host(vec![pin(&other)], true), false),
+            ("insecure and pinned", host(vec![pin(&pinned)], true), true),
+        ];
+        for (name, host, valid) in cases {
+            let config = UpstreamTlsConfig { trust: TrustConfig::default(), hosts: vec![host] };
+            let verifier = UpstreamVerifier::load(&config).await.unwrap();
+            let server_name = ServerName::try_from("pinned.example").unwrap();
+            let result = verifier.verify_server_cert(&pinned, &[], &server_name, &[], UnixTime::now());
+            assert_eq!(result.is_ok(), valid, "{}: {:?}", name, result);
+        }
+
+        std::fs::remove_file(&ca_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_invalid_pins() {
+        let config = UpstreamTlsConfig {
+            trust: TrustConfig::default(),
+            hosts: vec![HostTlsConfig {
+                host: "pinned.example".to_string(),
+                pins: vec!["sha256/not base64!".to_string()],
+                ..HostTlsConfig::default()
+            }],
+        };
+        assert!(UpstreamVerifier::load(&config).await.is_err());
+    }
+}
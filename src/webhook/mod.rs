@@ -0,0 +1,264 @@
+pub mod pod;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use anyhow::{anyhow, Result};
+use futures::{future, StreamExt};
+use http_body_util::BodyExt;
+use hudsucker::rcgen::{CertificateParams, DnType};
+use hyper::{body::Incoming, Method, Request, StatusCode};
+use k8s_openapi::api::admissionregistration::v1::MutatingWebhookConfiguration;
+use k8s_openapi::api::core::v1::{Pod, Secret};
+use kube::{Api, Client, ResourceExt};
+use kube::api::{Patch, PatchParams};
+use kube::core::{
+    admission::{AdmissionRequest, AdmissionResponse, AdmissionReview},
+    DynamicObject,
+};
+use kube::runtime::{watcher, WatchStreamExt};
+use log::{error, info, warn};
+use serde_json::json;
+use time::OffsetDateTime;
+use tokio::time::Instant;
+use crate::ca::{CaMaterial, BUNDLE_KEY, CERT_KEY, KEY_KEY};
+use crate::metrics;
+use crate::server::{self, tls::CertResolver};
+use crate::webhook::pod::InjectionSettings;
+
+const MANAGER: &str = "auth-bridge";
+const MUTATE_PATH: &str = "/mutate";
+const CERT_VALIDITY: Duration = Duration::from_secs(90 * 24 * 3600);
+/// How long to wait before issuing the certificate again after a failure.
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(clap::Args, Debug, Clone)]
+pub struct Args {
+    /// serve the mutating admission webhook that wires opted-in pods to the proxy
+    #[arg(long = "webhook")]
+    pub enabled: bool,
+
+    /// address of the webhook listener
+    #[arg(long = "webhook-addr", default_value = "0.0.0.0:9443")]
+    pub addr: SocketAddr,
+
+    /// name of the service in front of the webhook, used for its serving certificate
+    #[arg(long = "webhook-service", default_value = "auth-bridge-webhook")]
+    pub service: String,
+
+    /// namespace of the webhook service
+    #[arg(long = "webhook-namespace", default_value = "auth-bridge")]
+    pub namespace: String,
+
+    /// name of the MutatingWebhookConfiguration whose caBundle is kept up to date
+    #[arg(long = "webhook-configuration", default_value = "auth-bridge")]
+    pub configuration: String,
+
+    /// proxy URL injected as HTTP_PROXY and HTTPS_PROXY
+    #[arg(long, default_value = "http://auth-bridge-proxy.auth-bridge:80")]
+    pub proxy_url: String,
+
+    /// entries injected as NO_PROXY
+    #[arg(long, value_delimiter = ',', default_value = "localhost,127.0.0.1,.svc,.cluster.local")]
+    pub no_proxy: Vec<String>,
+
+    /// pod and service CIDRs of the cluster, appended to NO_PROXY
+    #[arg(long, value_delimiter = ',')]
+    pub cluster_cidr: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    pub args: Args,
+    pub ca_secret_namespace: String,
+    pub ca_secret_name: String,
+    pub ca_configmap: String,
+}
+
+/// Serves the webhook with a certificate signed by the proxy CA, which is re-issued
+/// whenever the CA secret changes and after two thirds of its lifetime.
+///
+/// Every replica serves the webhook, only one runs `sync_configuration`.
+pub async fn run(client: Client, config: WebhookConfig) -> Result<()> {
+    let resolver = Arc::new(CertResolver::default());
+    tokio::spawn(watch_ca(client, config.clone(), resolver.clone()));
+
+    let mut no_proxy = config.args.no_proxy.clone();
+    no_proxy.extend(config.args.cluster_cidr.iter().cloned());
+    let settings = Arc::new(InjectionSettings {
+        proxy_url: config.args.proxy_url.clone(),
+        no_proxy,
+        ca_configmap: config.ca_configmap.clone(),
+    });
+
+    server::serve(config.args.addr, Some(resolver.server_config()), move |req| {
+        let settings = settings.clone();
+        async move { handle(req, &settings).await }
+    }).await
+}
+
+async fn handle(req: Request<Incoming>, settings: &InjectionSettings) -> server::Response {
+    if req.method() != Method::POST || req.uri().path() != MUTATE_PATH {
+        return server::response(StatusCode::NOT_FOUND, "not found");
+    }
+
+    let body = match req.into_body().collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(e) => return server::response(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+
+    let review: AdmissionReview<Pod> = match serde_json::from_slice(&body) {
+        Ok(review) => review,
+        Err(e) => return server::response(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+
+    let request: AdmissionRequest<Pod> = match review.try_into() {
+        Ok(request) => request,
+        Err(e) => return server::response(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+
+    let review: AdmissionReview<DynamicObject> = mutate(&request, settings).into_review();
+    server::json_response(&review)
+}
+
+fn mutate(request: &AdmissionRequest<Pod>, settings: &InjectionSettings) -> AdmissionResponse {
+    let response = AdmissionResponse::from(request);
+    let Some(original) = request.object.as_ref() else {
+        return response;
+    };
+    let Some(mutated) = pod::inject(original, settings) else {
+        return response;
+    };
+
+    let patch = match (serde_json::to_value(original), serde_json::to_value(&mutated)) {
+        (Ok(original), Ok(mutated)) => json_patch::diff(&original, &mutated),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("failed to serialize pod: {}", e);
+            return response;
+        }
+    };
+
+    info!("injecting proxy settings into pod {}/{}", request.namespace.clone().unwrap_or_default(), original.name_any());
+    match response.clone().with_patch(patch) {
+        Ok(response) => response,
+        Err(e) => {
+            error!("failed to build patch: {}", e);
+            response
+        }
+    }
+}
+
+async fn watch_ca(client: Client, config: WebhookConfig, resolver: Arc<CertResolver>) {
+    let api = Api::<Secret>::namespaced(client.clone(), &config.ca_secret_namespace);
+    let wc = watcher::Config::default().fields(&format!("metadata.name={}", config.ca_secret_name));
+
+    let mut stream = watcher(api, wc).applied_objects().boxed();
+    let mut current: Option<Secret> = None;
+    let mut renew_at: Option<Instant> = None;
+    loop {
+        let renewal = async {
+            match renew_at {
+                Some(at) => tokio::time::sleep_until(at).await,
+                None => future::pending().await,
+            }
+        };
+        let secret = tokio::select! {
+            event = stream.next() => match event {
+                Some(Ok(secret)) => secret,
+                Some(Err(e)) => {
+                    error!("CA secret watch error: {}", e);
+                    continue;
+                }
+                None => return,
+            },
+            _ = renewal => match current.take() {
+                Some(secret) => secret,
+                None => continue,
+            },
+        };
+
+        let result = rotate(&config, &resolver, &secret);
+        metrics::reconciled("webhook-certificate", &result);
+        renew_at = Some(match result {
+            Ok(not_after) => renewal_time(not_after),
+            Err(e) => {
+                error!("failed to issue webhook certificate: {}", e);
+                Instant::now() + RETRY_INTERVAL
+            }
+        });
+        current = Some(secret);
+    }
+}
+
+/// Two thirds into the remaining lifetime of a certificate expiring at `not_after`.
+fn renewal_time(not_after: OffsetDateTime) -> Instant {
+    let remaining = (not_after - OffsetDateTime::now_utc()).max(time::Duration::ZERO);
+    let remaining = Duration::try_from(remaining).unwrap_or_default();
+    Instant::now() + (remaining * 2 / 3).max(RETRY_INTERVAL)
+}
+
+/// Issues a new serving certificate and returns when it expires.
+fn rotate(config: &WebhookConfig, resolver: &CertResolver, secret: &Secret) -> Result<OffsetDateTime> {
+    let data = secret.data.as_ref().ok_or(anyhow!("secret {} is empty", secret.name_any()))?;
+    let ca = CaMaterial::from_data(data, KEY_KEY, CERT_KEY)
+        .ok_or(anyhow!("secret {} does not contain {} and {}", secret.name_any(), KEY_KEY, CERT_KEY))??;
+
+    let service = format!("{}.{}.svc", config.args.service, config.args.namespace);
+    let dns_names = vec![service.clone(), format!("{}.cluster.local", service)];
+    let mut params = CertificateParams::new(dns_names)?;
+    params.distinguished_name.push(DnType::CommonName, service);
+    let issued = ca.issue(params, CERT_VALIDITY)?;
+    resolver.set_pem(issued.cert_pem.as_bytes(), issued.key_pem.as_bytes())?;
+    info!("issued webhook certificate expiring at {}", issued.not_after);
+
+    Ok(issued.not_after)
+}
+
+/// Keeps the caBundle of the MutatingWebhookConfiguration equal to the trust bundle in the CA secret.
+pub async fn sync_configuration(client: Client, config: WebhookConfig) {
+    let mut interval = tokio::time::interval(RETRY_INTERVAL);
+    loop {
+        interval.tick().await;
+        let result = update_configuration(&client, &config).await;
+        metrics::reconciled("webhook-configuration", &result);
+        if let Err(e) = result {
+            error!("failed to update MutatingWebhookConfiguration {}: {}", config.args.configuration, e);
+        }
+    }
+}
+
+async fn update_configuration(client: &Client, config: &WebhookConfig) -> Result<()> {
+    let secrets = Api::<Secret>::namespaced(client.clone(), &config.ca_secret_namespace);
+    let Some(secret) = secrets.get_opt(&config.ca_secret_name).await? else {
+        // nothing to trust until the CA has been generated
+        return Ok(());
+    };
+    let data = secret.data.as_ref().ok_or(anyhow!("secret {} is empty", secret.name_any()))?;
+    let bundle = data.get(BUNDLE_KEY).or_else(|| data.get(CERT_KEY))
+        .ok_or(anyhow!("secret {} contains neither {} nor {}", secret.name_any(), BUNDLE_KEY, CERT_KEY))?;
+
+    let api = Api::<MutatingWebhookConfiguration>::all(client.clone());
+    let Some(configuration) = api.get_opt(&config.args.configuration).await? else {
+        warn!("MutatingWebhookConfiguration {} not found", config.args.configuration);
+        return Ok(());
+    };
+
+    let webhooks = configuration.webhooks.unwrap_or_default();
+    if webhooks.iter().all(|webhook| webhook.client_config.ca_bundle.as_ref() == Some(bundle)) {
+        return Ok(());
+    }
+
+    let patch = json!({
+        "apiVersion": "admissionregistration.k8s.io/v1",
+        "kind": "MutatingWebhookConfiguration",
+        "metadata": { "name": config.args.configuration },
+        "webhooks": webhooks.iter().map(|webhook| json!({
+            "name": webhook.name,
+            "clientConfig": { "caBundle": bundle },
+        })).collect::<Vec<_>>(),
+    });
+    api.patch(&config.args.configuration, &PatchParams::apply(MANAGER).force(), &Patch::Apply(&patch)).await?;
+    info!("updated caBundle of MutatingWebhookConfiguration {}", config.args.configuration);
+
+    Ok(())
+}
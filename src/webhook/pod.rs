@@ -0,0 +1,125 @@
+use k8s_openapi::api::core::v1::{ConfigMapVolumeSource, Container, EnvVar, Pod, Volume, VolumeMount};
+
+/// Pods opt in by setting this annotation to `"true"`.
+pub const INJECT_ANNOTATION: &str = "auth-bridge.dev/inject";
+/// Comma separated names of containers that must not be wired to the proxy.
+pub const SKIP_CONTAINERS_ANNOTATION: &str = "auth-bridge.dev/skip-containers";
+/// Comma separated entries appended to `NO_PROXY`.
+pub const NO_PROXY_ANNOTATION: &str = "auth-bridge.dev/no-proxy";
+/// Set on mutated pods, so a pod is never injected twice.
+pub const STATUS_ANNOTATION: &str = "auth-bridge.dev/status";
+
+const INJECTED: &str = "injected";
+const CA_VOLUME: &str = "auth-bridge-ca";
+const CA_MOUNT_PATH: &str = "/etc/auth-bridge/ca";
+const CA_FILE: &str = "/etc/auth-bridge/ca/ca.crt";
+const SYSTEM_CA_FILE: &str = "/etc/auth-bridge/ca/ca-certificates.crt";
+const PROXY_ENV: [&str; 4] = ["HTTP_PROXY", "HTTPS_PROXY", "http_proxy", "https_proxy"];
+const NO_PROXY_ENV: [&str; 2] = ["NO_PROXY", "no_proxy"];
+/// Variables adding to the system trust store, pointed at the proxy CA alone.
+const EXTRA_CA_ENV: [&str; 1] = ["NODE_EXTRA_CA_CERTS"];
+/// Variables replacing the system trust store, pointed at the system roots plus the proxy CA.
+const SYSTEM_CA_ENV: [&str; 4] = ["SSL_CERT_FILE", "REQUESTS_CA_BUNDLE", "CURL_CA_BUNDLE", "GIT_SSL_CAINFO"];
+
+#[derive(Clone, Debug)]
+pub struct InjectionSettings {
+    pub proxy_url: String,
+    pub no_proxy: Vec<String>,
+    pub ca_configmap: String,
+}
+
+/// Returns the mutated pod, or `None` when the pod did not opt in or was already injected.
+pub fn inject(pod: &Pod, settings: &InjectionSettings) -> Option<Pod> {
+    let annotations = pod.metadata.annotations.clone().unwrap_or_default();
+    if annotations.get(INJECT_ANNOTATION).map(String::as_str) != Some("true") {
+        return None;
+    }
+    if annotations.get(STATUS_ANNOTATION).map(String::as_str) == Some(INJECTED) {
+        return None;
+    }
+
+    let skipped: Vec<&str> = annotations.get(SKIP_CONTAINERS_ANNOTATION)
+        .map(|value| value.split(',').map(str::trim).collect())
+        .unwrap_or_default();
+
+    let mut no_proxy = settings.no_proxy.clone();
+    if let Some(extra) = annotations.get(NO_PROXY_ANNOTATION) {
+        no_proxy.extend(extra.split(',').map(str::trim).filter(|s| !s.is_empty()).map(String::from));
+    }
+    let no_proxy = no_proxy.join(",");
+
+    let mut pod = pod.clone();
+    let spec = pod.spec.as_mut()?;
+
+    let mut injected = false;
+    let init_containers = spec.init_containers.iter_mut().flatten();
+    for container in spec.containers.iter_mut().chain(init_containers) {
+        if skipped.contains(&container.name.as_str()) {
+            continue;
+        }
+        inject_container(container, settings, &no_proxy);
+        injected = true;
+    }
+
+    if !injected {
+        return None;
+    }
+
+    let volumes = spec.volumes.get_or_insert_with(Vec::new);
+    if !volumes.iter().any(|volume| volume.name == CA_VOLUME) {
+        volumes.push(Volume {
+            name: CA_VOLUME.to_string(),
+            config_map: Some(ConfigMapVolumeSource {
+                name: Some(settings.ca_configmap.clone()),
+                // the bundle may not be published to the namespace yet, which must not block the pod
+                optional: Some(true),
+                ..ConfigMapVolumeSource::default()
+            }),
+            ..Volume::default()
+        });
+    }
+
+    pod.metadata.annotations
+        .get_or_insert_with(Default::default)
+        .insert(STATUS_ANNOTATION.to_string(), INJECTED.to_string());
+
+    Some(pod)
+}
+
+fn inject_container(container: &mut Container, settings: &InjectionSettings, no_proxy: &str) {
+    let env = container.env.get_or_insert_with(Vec::new);
+    for name in PROXY_ENV {
+        set_env(env, name, &settings.proxy_url);
+    }
+    for name in NO_PROXY_ENV {
+        set_env(env, name, no_proxy);
+    }
+    for name in EXTRA_CA_ENV {
+        set_env(env, name, CA_FILE);
+    }
+    for name in SYSTEM_CA_ENV {
+        set_env(env, name, SYSTEM_CA_FILE);
+    }
+
+    let mounts = container.volume_mounts.get_or_insert_with(Vec::new);
+    if !mounts.iter().any(|mount| mount.name == CA_VOLUME) {
+        mounts.push(VolumeMount {
+            name: CA_VOLUME.to_string(),
+            mount_path: CA_MOUNT_PATH.to_string(),
+            read_only: Some(true),
+            ..VolumeMount::default()
+        });
+    }
+}
+
+/// Variables the container already defines win over the injected ones.
+fn set_env(env: &mut Vec<EnvVar>, name: &str, value: &str) {
+    if env.iter().any(|var| var.name == name) {
+        return;
+    }
+    env.push(EnvVar {
+        name: name.to_string(),
+        value: Some(value.to_string()),
+        ..EnvVar::default()
+    });
+}